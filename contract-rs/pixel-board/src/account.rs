@@ -3,32 +3,61 @@ use std::convert::TryInto;
 use crate::*;
 
 use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+use near_contract_standards::storage_management::StorageManagement;
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
 use near_sdk::json_types::{ValidAccountId, U128};
 use near_sdk::{env, near_bindgen, AccountId, PromiseOrValue};
 
 pub const ONE_NEAR: Balance = 1_000_000_000_000_000_000_000_000;
-pub const MIN_AMOUNT_FOR_DISCOUNT: Balance = 5 * ONE_NEAR;
 pub const DEFAULT_MILK_BALANCE: u32 = 2;
+/// Harberger tax rate is in basis points of the declared value per day.
+const TAX_DENOMINATOR: Balance = 10_000 * 24 * 60 * 60 * FROM_NANO as Balance;
 
 pub type AccountIndex = u32;
 
+// not boxed: the size is used to compute the storage deposit
+#[allow(clippy::large_enum_variant)]
 #[derive(BorshDeserialize, BorshSerialize)]
 pub enum UpgradableAccount {
-    BananaAccount(Account),
+    BananaAccount(AccountV1),
+    CheddarAccount(Account),
 }
 
 impl From<UpgradableAccount> for Account {
     fn from(account: UpgradableAccount) -> Self {
         match account {
-            UpgradableAccount::BananaAccount(account) => account,
+            UpgradableAccount::BananaAccount(account) => account.into(),
+            UpgradableAccount::CheddarAccount(account) => account,
         }
     }
 }
 
 impl From<Account> for UpgradableAccount {
     fn from(account: Account) -> Self {
-        UpgradableAccount::BananaAccount(account)
+        UpgradableAccount::CheddarAccount(account)
+    }
+}
+
+/// Account stored before the Harberger tax, deposits, stats and teams were
+/// added. Converted to `Account` when loaded.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct AccountV1 {
+    pub account_id: AccountId,
+    pub account_index: AccountIndex,
+    pub balances: Vec<Balance>,
+    pub num_pixels: u32,
+    pub claim_timestamp: u64,
+    pub mint_funded: bool,
+}
+
+impl From<AccountV1> for Account {
+    fn from(a: AccountV1) -> Self {
+        let mut account = Account::new(a.account_id, a.account_index);
+        account.balances = a.balances;
+        account.num_pixels = a.num_pixels;
+        account.claim_timestamp = a.claim_timestamp;
+        account.mint_funded = a.mint_funded;
+        account
     }
 }
 
@@ -41,6 +70,77 @@ pub struct Account {
     pub num_pixels: u32,
     pub claim_timestamp: u64,
     pub mint_funded: bool,
+    /// sum of the self-assessed prices (in Milk) of the owned pixels
+    pub declared_value: Balance,
+    /// Harberger tax accrued but not yet charged, in `TAX_DENOMINATOR` units
+    pub accrued_tax: Balance,
+    pub tax_timestamp: u64,
+    /// Cheddar deposited with `ft_transfer_call`, held by the contract.
+    /// `balances[Cheddar]` only keeps the farmed (not yet minted) Cheddar.
+    pub deposited_cheddar: Balance,
+    /// Cheddar being minted or transferred to the account, not confirmed yet
+    pub pending_withdrawal: Balance,
+    pub stats: AccountStats,
+    pub team: Option<TeamId>,
+    /// season the team bonus was last claimed for
+    pub team_bonus_claimed: Option<u32>,
+    pub draws: DrawHistory,
+    /// pending hidden draw, see `Place::commit_draw`
+    pub commitment: Option<DrawCommitment>,
+}
+
+/// Lifetime totals of an account.
+#[derive(BorshDeserialize, BorshSerialize, Default)]
+pub struct AccountStats {
+    pub milk_bought_with_near: Balance,
+    pub milk_bought_with_cheddar: Balance,
+    pub milk_bought_with_tokens: Balance,
+    pub milk_spent: Balance,
+    pub pixels_painted: u64,
+    /// pixels overwritten by other accounts or foreclosed
+    pub pixels_lost: u64,
+    pub farmed_cheddar: Balance,
+    /// farmed Cheddar minted to the account or to another receiver
+    pub withdrawn_cheddar: Balance,
+    /// season prizes and team bonuses won
+    pub prizes: Balance,
+    pub first_activity: u64,
+    pub last_activity: u64,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct HumanAccountStats {
+    pub milk_bought_with_near: U128,
+    pub milk_bought_with_cheddar: U128,
+    pub milk_bought_with_tokens: U128,
+    pub milk_spent: U128,
+    pub pixels_painted: u64,
+    pub pixels_lost: u64,
+    pub farmed_cheddar: U128,
+    pub withdrawn_cheddar: U128,
+    pub prizes: U128,
+    /// timestamps in seconds
+    pub first_activity: u64,
+    pub last_activity: u64,
+}
+
+impl From<AccountStats> for HumanAccountStats {
+    fn from(s: AccountStats) -> Self {
+        Self {
+            milk_bought_with_near: s.milk_bought_with_near.into(),
+            milk_bought_with_cheddar: s.milk_bought_with_cheddar.into(),
+            milk_bought_with_tokens: s.milk_bought_with_tokens.into(),
+            milk_spent: s.milk_spent.into(),
+            pixels_painted: s.pixels_painted,
+            pixels_lost: s.pixels_lost,
+            farmed_cheddar: s.farmed_cheddar.into(),
+            withdrawn_cheddar: s.withdrawn_cheddar.into(),
+            prizes: s.prizes.into(),
+            first_activity: s.first_activity / FROM_NANO,
+            last_activity: s.last_activity / FROM_NANO,
+        }
+    }
 }
 
 #[derive(Serialize)]
@@ -51,6 +151,10 @@ pub struct HumanAccount {
     pub avocado_balance: U128,
     pub banana_balance: U128,
     pub num_pixels: u32,
+    pub declared_value: U128,
+    pub deposited_cheddar: U128,
+    pub pending_withdrawal: U128,
+    pub team: Option<TeamId>,
 }
 
 impl From<Account> for HumanAccount {
@@ -61,6 +165,10 @@ impl From<Account> for HumanAccount {
             avocado_balance: account.balances[Berry::Milk as usize].into(),
             banana_balance: account.balances[Berry::Cheddar as usize].into(),
             num_pixels: account.num_pixels,
+            declared_value: account.declared_value.into(),
+            deposited_cheddar: account.deposited_cheddar.into(),
+            pending_withdrawal: account.pending_withdrawal.into(),
+            team: account.team,
         }
     }
 }
@@ -74,6 +182,21 @@ impl Account {
             num_pixels: 0,
             claim_timestamp: env::block_timestamp(),
             mint_funded: false,
+            declared_value: 0,
+            accrued_tax: 0,
+            tax_timestamp: env::block_timestamp(),
+            deposited_cheddar: 0,
+            pending_withdrawal: 0,
+            stats: AccountStats {
+                first_activity: env::block_timestamp(),
+                // set on the first action, see `Place::record_activity`
+                last_activity: 0,
+                ..Default::default()
+            },
+            team: None,
+            team_bonus_claimed: None,
+            draws: DrawHistory::default(),
+            commitment: None,
         }
     }
 
@@ -89,51 +212,142 @@ impl Account {
             && (milk == 0 || milk == 2) // 2 = the default balance
             && self.balances[1] == 0
             && self.num_pixels == 0
+            && self.deposited_cheddar == 0
+            && self.pending_withdrawal == 0
+            && self.commitment.is_none()
     }
 
-    /// Buying pixel (milk) tokens for drawing pixels
-    pub fn buy_tokens(&mut self, near_amount: Balance, milk_price: Balance) -> Balance {
-        let amount = if near_amount >= MIN_AMOUNT_FOR_DISCOUNT {
-            near_amount / milk_price / 5 * 6 // applying discount
-        } else {
-            near_amount / milk_price
-        };
+    /// Buying pixel (milk) tokens for drawing pixels. `amount` is the
+    /// number of tokens before the discount.
+    pub fn buy_tokens(
+        &mut self,
+        near_amount: Balance,
+        amount: Balance,
+        tiers: &[DiscountTier],
+    ) -> Balance {
+        let (amount, tier) = apply_discount(tiers, near_amount, amount);
         let near_int = near_amount / ONE_NEAR;
         env::log(
             format!(
-                "Purchased {} Milk tokens for {}.{:03} NEAR",
+                "Purchased {} Milk tokens for {}.{:03} NEAR{}",
                 amount,
                 near_int,
                 (near_amount - near_int * ONE_NEAR) / (ONE_NEAR / 1000),
+                discount_log(tiers, tier),
             )
             .as_bytes(),
         );
         self.balances[Berry::Milk as usize] += amount;
+        self.stats.milk_bought_with_near += amount;
         amount
     }
 
-    pub fn buy_milk_with_cheddar(&mut self, cheddar: Balance, milk_price: Balance) -> Balance {
+    /// Buys Milk spending the deposited Cheddar first, then the farmed one.
+    pub fn buy_milk_with_cheddar(
+        &mut self,
+        cheddar: Balance,
+        milk_price: Balance,
+        tiers: &[DiscountTier],
+    ) -> Balance {
         let owned_cheddar = self.balances[Berry::Cheddar as usize];
-        assert!(owned_cheddar >= cheddar, "not enough balance");
-        let amount = cheddar / milk_price;
+        assert!(
+            owned_cheddar + self.deposited_cheddar >= cheddar,
+            "not enough balance"
+        );
+        let (amount, tier) = apply_discount(tiers, cheddar, cheddar / milk_price);
         let cheddar_int = cheddar / ONE_NEAR;
         env::log(
             format!(
-                "Purchased {} Milk tokens for {}.{:03} Cheddar",
+                "Purchased {} Milk tokens for {}.{:03} Cheddar{}",
                 amount,
                 cheddar_int,
                 (cheddar - cheddar_int * ONE_NEAR) / (ONE_NEAR / 1000),
+                discount_log(tiers, tier),
             )
             .as_bytes(),
         );
         self.balances[Berry::Milk as usize] += amount;
-        self.balances[Berry::Cheddar as usize] = owned_cheddar - cheddar;
+        self.stats.milk_bought_with_cheddar += amount;
+        let from_deposit = std::cmp::min(cheddar, self.deposited_cheddar);
+        self.deposited_cheddar -= from_deposit;
+        self.balances[Berry::Cheddar as usize] = owned_cheddar - (cheddar - from_deposit);
         amount
     }
 
+    /// Buying Milk with a whitelisted payment token. `paid` must be a
+    /// multiple of `token.milk_price`.
+    /// Discount `tiers` (in NEAR) are applied to the NEAR value of the bought
+    /// Milk at the `near_milk_price`.
+    pub fn buy_milk_with_token(
+        &mut self,
+        paid: Balance,
+        token_id: &AccountId,
+        token: &PaymentToken,
+        near_milk_price: Balance,
+        tiers: &[DiscountTier],
+    ) -> Balance {
+        let amount = paid / token.milk_price.0;
+        let (amount, tier) = apply_discount(tiers, amount * near_milk_price, amount);
+        let unit: Balance = 10u128.pow(u32::from(token.decimals));
+        let paid_int = paid / unit;
+        env::log(
+            format!(
+                "Purchased {} Milk tokens for {}.{:03} {}{}",
+                amount,
+                paid_int,
+                (paid - paid_int * unit) * 1000 / unit,
+                token_id,
+                discount_log(tiers, tier),
+            )
+            .as_bytes(),
+        );
+        self.balances[Berry::Milk as usize] += amount;
+        self.stats.milk_bought_with_tokens += amount;
+        amount
+    }
+
+    /// Updates the account balance: farms Cheddar and pays the Harberger tax.
+    /// Returns number of farmed tokens and `true` if the Milk balance didn't
+    /// cover the tax (the owned pixels must be foreclosed).
+    pub fn touch(
+        &mut self,
+        reward_rate: Balance,
+        tax_rate: u32,
+        new_start: u64,
+        ends: u64,
+    ) -> (Balance, bool) {
+        let insolvent = !self.pay_tax(tax_rate, ends);
+        (self.farm(reward_rate, new_start, ends), insolvent)
+    }
+
+    /// Charges the Harberger tax accrued since the last call.
+    /// Returns false if the Milk balance is not enough to pay it.
+    fn pay_tax(&mut self, tax_rate: u32, ends: u64) -> bool {
+        let now = std::cmp::min(env::block_timestamp(), ends);
+        if now <= self.tax_timestamp {
+            return true;
+        }
+        let time_diff = now - self.tax_timestamp;
+        self.tax_timestamp = now;
+        if tax_rate == 0 || self.declared_value == 0 {
+            return true;
+        }
+        self.accrued_tax +=
+            self.declared_value * Balance::from(tax_rate) * Balance::from(time_diff);
+        let tax = self.accrued_tax / TAX_DENOMINATOR;
+        self.accrued_tax %= TAX_DENOMINATOR;
+        let milk = &mut self.balances[Berry::Milk as usize];
+        if *milk < tax {
+            *milk = 0;
+            return false;
+        }
+        *milk -= tax;
+        true
+    }
+
     /// Updates the account balance, returns number of farmed tokens.
     /// + `ends` is the old end
-    pub fn touch(&mut self, reward_rate: Balance, new_start: u64, ends: u64) -> Balance {
+    fn farm(&mut self, reward_rate: Balance, new_start: u64, ends: u64) -> Balance {
         let old_ends: u64 = 1662008400 * FROM_NANO;
 
         assert!(
@@ -161,21 +375,91 @@ impl Account {
         let farmed = Balance::from(self.num_pixels) * Balance::from(time_diff) * reward_rate;
         self.claim_timestamp = claim_timestamp;
         self.balances[Berry::Cheddar as usize] += farmed;
+        self.stats.farmed_cheddar += farmed;
         farmed
     }
 
-    pub fn charge(&mut self, berry: Berry, num_pixels: u32) -> Balance {
-        let cost = Balance::from(num_pixels);
+    pub fn charge(&mut self, berry: Berry, cost: Balance) -> Balance {
         assert!(
             self.balances[berry as usize] >= cost,
             "Not enough balance to draw pixels"
         );
         self.balances[berry as usize] -= cost;
+        if let Berry::Milk = berry {
+            self.stats.milk_spent += cost;
+        }
         cost
     }
 }
 
+/// Bonus Milk granted for purchases of at least `threshold` tokens.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DiscountTier {
+    /// minimum payment (in NEAR or Cheddar)
+    pub threshold: U128,
+    /// bonus in basis points of the bought Milk
+    pub bonus: u32,
+}
+
+pub(crate) fn assert_valid_tiers(tiers: &[DiscountTier]) {
+    for w in tiers.windows(2) {
+        assert!(
+            w[0].threshold.0 < w[1].threshold.0,
+            "tiers must be sorted by threshold"
+        );
+    }
+}
+
+/// Applies the bonus of the highest tier reached by the `paid` amount.
+/// Returns the Milk amount with the bonus and the index of the applied tier.
+pub(crate) fn apply_discount(
+    tiers: &[DiscountTier],
+    paid: Balance,
+    amount: Balance,
+) -> (Balance, Option<usize>) {
+    match tiers.iter().rposition(|t| paid >= t.threshold.0) {
+        Some(i) => (
+            amount + amount * Balance::from(tiers[i].bonus) / 10_000,
+            Some(i),
+        ),
+        None => (amount, None),
+    }
+}
+
+fn discount_log(tiers: &[DiscountTier], tier: Option<usize>) -> String {
+    match tier {
+        Some(i) => format!(" with discount tier {} (+{} bps bonus)", i, tiers[i].bonus),
+        None => String::new(),
+    }
+}
+
 impl Place {
+    /// Returns amount of Milk (without the discount) which can be bought for
+    /// `near_amount` and the NEAR it costs.
+    pub(crate) fn milk_for_near(&self, near_amount: Balance) -> (Balance, Balance) {
+        match &self.milk_curve {
+            Some(curve) => curve.buy(self.bought_milk, near_amount),
+            None => {
+                let amount = near_amount / self.milk_price;
+                (amount, amount * self.milk_price)
+            }
+        }
+    }
+
+    /// Registers the account paying the storage from the storage pool.
+    /// Returns false if the pool is not big enough.
+    pub(crate) fn register_from_storage_pool(&mut self, account_id: &AccountId) -> bool {
+        let cost = self.storage_balance_bounds().min.0;
+        if self.storage_pool < cost {
+            return false;
+        }
+        self.storage_pool -= cost;
+        let account = self.get_mut_account(account_id);
+        self.save_account(account);
+        true
+    }
+
     pub(crate) fn get_internal_account_by_id(&self, account_id: &AccountId) -> Option<Account> {
         self.account_indices
             .get(&account_id)
@@ -185,8 +469,10 @@ impl Place {
     pub(crate) fn get_mut_account(&mut self, account_id: &AccountId) -> Account {
         let mut account = self
             .get_internal_account_by_id(account_id)
-            .unwrap_or_else(|| Account::new(account_id.clone(), self.num_accounts));
+            .unwrap_or_else(|| Account::new(account_id.clone(), self.next_account_index()));
+        self.drop_expired_undo_record(account_id);
         self.touch(&mut account);
+        self.record_activity(&mut account);
         account
     }
 
@@ -199,23 +485,104 @@ impl Place {
             .map(|account| account.into())
     }
 
-    /// Updates account state & farmed balance
+    /// Updates account state & farmed balance, forecloses pixels of accounts
+    /// which can't pay the Harberger tax.
     pub(crate) fn touch(&mut self, account: &mut Account) {
-        let farmed = account.touch(self.reward_rate, self.starts, self.ends);
+        let (farmed, insolvent) = account.touch(
+            self.reward_rate,
+            self.harberger_tax_rate,
+            self.starts,
+            self.ends,
+        );
         if farmed > 0 {
             self.farmed_cheddar += farmed;
+            self.record_daily(|s| s.farmed_cheddar = (s.farmed_cheddar.0 + farmed).into());
+        }
+        if insolvent {
+            self.foreclose(account);
+        }
+    }
+
+    /// Transfers all pixels of the account to the contract account.
+    fn foreclose(&mut self, account: &mut Account) {
+        let (num_pixels, value) = self.board.foreclose(account.account_index);
+        log!("Foreclosed {} pixels of {}", num_pixels, account.account_id);
+        account.num_pixels -= num_pixels;
+        self.update_pixel_counts(account, account.num_pixels + num_pixels);
+        account.declared_value -= value;
+        account.accrued_tax = 0;
+        account.stats.pixels_lost += u64::from(num_pixels);
+
+        let mut a = self.get_internal_account_by_index(0).unwrap();
+        self.touch(&mut a);
+        a.num_pixels += num_pixels;
+        self.save_account(a);
+    }
+
+    /// Updates `num_pixel_owners` and the team territory after the account's
+    /// `num_pixels` changed from `old_num_pixels`.
+    pub(crate) fn update_pixel_counts(&mut self, account: &Account, old_num_pixels: u32) {
+        if account.account_index == 0 {
+            return;
+        }
+        if let Some(team) = account.team {
+            let team = &mut self.teams[team as usize];
+            team.territory = team.territory + account.num_pixels - old_num_pixels;
+        }
+        match (old_num_pixels > 0, account.num_pixels > 0) {
+            (false, true) => self.num_pixel_owners += 1,
+            (true, false) => self.num_pixel_owners -= 1,
+            _ => {}
         }
     }
 
+    /// Index for a new account: a freed index of a deleted account is reused
+    /// first.
+    pub(crate) fn next_account_index(&self) -> AccountIndex {
+        self.free_account_indices
+            .last()
+            .copied()
+            .unwrap_or(self.num_accounts)
+    }
+
     pub(crate) fn save_account(&mut self, account: Account) {
         let account_index = account.account_index;
-        if account_index >= self.num_accounts {
+        if !self.account_indices.contains_key(&account.account_id) {
             self.account_indices
                 .insert(&account.account_id, &account_index);
-            self.num_accounts += 1;
+            if self.free_account_indices.last() == Some(&account_index) {
+                self.free_account_indices.pop();
+            } else {
+                self.num_accounts += 1;
+            }
         }
+        self.update_leaderboards(&account);
         self.accounts.insert(&account_index, &account.into());
     }
+
+    /// Removes the account from the state and frees its index for reuse.
+    /// Only empty accounts (without pixels) can be deleted, so no pixel
+    /// references the freed index.
+    pub(crate) fn delete_account(&mut self, account: &Account) {
+        assert!(
+            account.account_index != 0 && account.is_empty(),
+            "only empty accounts can be deleted"
+        );
+        self.accounts.remove(&account.account_index);
+        self.account_indices.remove(&account.account_id);
+        self.undo_records.remove(&account.account_id);
+        if account.mint_funded {
+            self.mint_funded -= 1;
+        }
+        if let Some(team) = account.team {
+            self.teams[team as usize].members -= 1;
+        }
+        self.board
+            .shields
+            .retain(|s| s.owner_id != account.account_index);
+        self.remove_from_leaderboards(account.account_index);
+        self.free_account_indices.push(account.account_index);
+    }
 }
 
 #[near_bindgen]
@@ -223,23 +590,61 @@ impl Place {
     pub fn get_account_by_index(&self, account_index: AccountIndex) -> Option<HumanAccount> {
         self.get_internal_account_by_index(account_index)
             .map(|mut account| {
-                account.touch(self.reward_rate, self.starts, self.ends);
+                account.touch(
+                    self.reward_rate,
+                    self.harberger_tax_rate,
+                    self.starts,
+                    self.ends,
+                );
                 account.into()
             })
     }
 
+    /// Returns accounts with indices in `[from_index, from_index + limit)`.
+    /// Deleted accounts are skipped.
+    pub fn get_accounts(&self, from_index: AccountIndex, limit: u32) -> Vec<HumanAccount> {
+        let to_index = std::cmp::min(from_index.saturating_add(limit), self.num_accounts);
+        (from_index..to_index)
+            .filter_map(|i| self.get_account_by_index(i))
+            .collect()
+    }
+
     pub fn get_account(&self, account_id: ValidAccountId) -> Option<HumanAccount> {
         self.get_internal_account_by_id(account_id.as_ref())
             .map(|mut account| {
-                account.touch(self.reward_rate, self.starts, self.ends);
+                account.touch(
+                    self.reward_rate,
+                    self.harberger_tax_rate,
+                    self.starts,
+                    self.ends,
+                );
                 account.into()
             })
     }
 
+    /// Lifetime totals of the account, including the Cheddar farmed so far.
+    pub fn get_account_stats(&self, account_id: ValidAccountId) -> Option<HumanAccountStats> {
+        self.get_internal_account_by_id(account_id.as_ref())
+            .map(|mut account| {
+                account.touch(
+                    self.reward_rate,
+                    self.harberger_tax_rate,
+                    self.starts,
+                    self.ends,
+                );
+                account.stats.into()
+            })
+    }
+
     // returns amount of Milk tokens
     pub fn get_account_balance(&self, account_id: ValidAccountId) -> u32 {
         if let Some(mut a) = self.get_internal_account_by_id(account_id.as_ref()) {
-            a.touch(self.reward_rate, self.starts, self.ends);
+            a.touch(
+                self.reward_rate,
+                self.harberger_tax_rate,
+                self.starts,
+                self.ends,
+            );
             return a.balances[Berry::Milk as usize].try_into().unwrap();
         }
         return DEFAULT_MILK_BALANCE;
@@ -251,21 +656,50 @@ impl Place {
             .unwrap_or(0)
     }
 
+    /// Returns amount of Cheddar being withdrawn (not confirmed yet).
+    pub fn get_pending_withdrawal(&self, account_id: ValidAccountId) -> U128 {
+        self.get_internal_account_by_id(account_id.as_ref())
+            .map(|account| account.pending_withdrawal)
+            .unwrap_or(0)
+            .into()
+    }
+
     pub fn get_account_id_by_index(&self, account_index: AccountIndex) -> Option<AccountId> {
         self.get_internal_account_by_index(account_index)
             .map(|account| account.account_id)
     }
 }
 
+/// Action executed with the Cheddar received in `ft_on_transfer`.
+/// Passed as JSON `msg`, eg: `{"action": "buy_milk"}` or
+/// `{"action": "draw", "pixels": [{"x": 1, "y": 2, "color": 255}]}`.
+#[derive(Deserialize)]
+#[serde(crate = "near_sdk::serde", tag = "action", rename_all = "snake_case")]
+pub enum TransferAction {
+    /// buys Milk with the whole deposit
+    BuyMilk,
+    /// buys the Milk missing to draw the pixels and draws them
+    Draw { pixels: Vec<SetPixelRequest> },
+    /// adds the whole deposit to the team bonus pool
+    FundTeamBonus,
+    /// adds the whole deposit to the prize of the current season
+    FundSeasonPrize,
+}
+
 // token deposits are done through NEP-141 ft_transfer_call to the NEARswap contract.
 #[near_bindgen]
 impl FungibleTokenReceiver for Place {
     /**
     FungibleTokenReceiver implementation
     Callback on receiving tokens by this contract.
-    Returns zero.
-    Panics when account is not registered or when receiving a wrong token. */
-    #[allow(unused_variables)]
+    Whitelisted payment tokens are used to buy Milk.
+    For Cheddar, if `msg` is a JSON encoded `TransferAction` the deposit is
+    used to buy Milk, draw pixels, fund the team bonus or the season prize,
+    otherwise it's added to the deposited Cheddar balance.
+    Unregistered senders are registered with NEAR from the storage pool. If the
+    pool is not big enough, the whole amount is returned.
+    Returns the unused amount.
+    Panics when receiving a wrong token. */
     fn ft_on_transfer(
         &mut self,
         sender_id: ValidAccountId,
@@ -276,17 +710,73 @@ impl FungibleTokenReceiver for Place {
 
         let token = env::predecessor_account_id();
         assert!(
-            token == self.cheddar,
-            "Only cheddar token transfers are accepted",
+            token == self.cheddar || self.payment_tokens.get(&token).is_some(),
+            "Token is not accepted as payment"
         );
         assert!(amount.0 > 0, "amount must be positive");
-        
-        let sender_id: &AccountId = sender_id.as_ref();
-        assert!(!self.blacklist.contains(&sender_id.clone()), "Account blacklisted");
 
-        let mut a = self.get_internal_account_by_id(sender_id).unwrap();
-        a.balances[Berry::Cheddar as usize] += amount.0;
+        let sender_id: AccountId = sender_id.into();
+        assert!(!self.blacklist.contains(&sender_id), "Account blacklisted");
+        if !self.account_indices.contains_key(&sender_id)
+            && !self.register_from_storage_pool(&sender_id)
+        {
+            log!("{} is not registered, returning the deposit", sender_id);
+            return PromiseOrValue::Value(amount);
+        }
+        if token != self.cheddar {
+            let unused = self.internal_buy_milk_with_token(&sender_id, &token, amount.0);
+            return PromiseOrValue::Value(unused.into());
+        }
+        let action: Option<TransferAction> = if msg.starts_with('{') {
+            Some(near_sdk::serde_json::from_str(&msg).expect("Invalid msg"))
+        } else {
+            None
+        };
+
+        match action {
+            Some(TransferAction::FundTeamBonus) => {
+                self.fund_team_bonus(amount.0);
+                return PromiseOrValue::Value(U128(0));
+            }
+            Some(TransferAction::FundSeasonPrize) => {
+                self.fund_season_prize(amount.0);
+                return PromiseOrValue::Value(U128(0));
+            }
+            _ => {}
+        }
+
+        let mut a = self.get_internal_account_by_id(&sender_id).unwrap();
+        self.touch(&mut a);
+        let used = match &action {
+            None | Some(TransferAction::FundTeamBonus) | Some(TransferAction::FundSeasonPrize) => {
+                amount.0
+            }
+            Some(TransferAction::BuyMilk) => amount.0 - amount.0 % self.milk_cheddar_price,
+            Some(TransferAction::Draw { pixels }) => {
+                let cost = self.board.quote(
+                    a.account_index,
+                    pixels,
+                    &self.pixel_pricing,
+                    self.harberger_tax_rate > 0,
+                );
+                let missing_milk = cost.saturating_sub(a.balances[Berry::Milk as usize]);
+                let needed = missing_milk * self.milk_cheddar_price;
+                assert!(needed <= amount.0, "Not enough Cheddar to draw the pixels");
+                needed
+            }
+        };
+        a.deposited_cheddar += used;
         self.save_account(a);
-        return PromiseOrValue::Value(U128(0));
+        self.deposited_cheddar += used;
+
+        if action.is_some() && used > 0 {
+            self.internal_buy_milk_with_cheddar(&sender_id, used);
+        }
+        if let Some(TransferAction::Draw { pixels }) = action {
+            if !pixels.is_empty() {
+                self.internal_draw(&sender_id, &pixels);
+            }
+        }
+        PromiseOrValue::Value(U128(amount.0 - used))
     }
 }
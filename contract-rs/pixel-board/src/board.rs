@@ -10,6 +10,10 @@ use std::collections::HashMap;
 pub const BOARD_WIDTH: u8 = 80;
 pub const BOARD_HEIGHT: u8 = 80;
 pub const TOTAL_NUM_PIXELS: u32 = 80 * 80;
+/// maximum number of active shields of one account
+pub const MAX_ACTIVE_SHIELDS: usize = 10;
+/// Pixel colors are counted in buckets of 2 bits per RGB channel.
+pub const NUM_COLOR_BUCKETS: usize = 64;
 
 #[derive(BorshDeserialize, BorshSerialize, Copy, Clone)]
 pub struct Pixel {
@@ -26,6 +30,19 @@ impl Default for Pixel {
     }
 }
 
+/// Index of the color bucket: the 2 most significant bits of every channel.
+pub fn color_bucket(color: u32) -> usize {
+    let (r, g, b) = ((color >> 22) & 3, (color >> 14) & 3, (color >> 6) & 3);
+    (r << 4 | g << 2 | b) as usize
+}
+
+/// Color buckets of an empty board.
+pub fn initial_color_buckets() -> Vec<u32> {
+    let mut buckets = vec![0; NUM_COLOR_BUCKETS];
+    buckets[color_bucket(Pixel::default().color)] = TOTAL_NUM_PIXELS;
+    buckets
+}
+
 #[derive(BorshDeserialize, BorshSerialize)]
 pub struct PixelLine(pub Vec<Pixel>);
 
@@ -35,10 +52,146 @@ impl Default for PixelLine {
     }
 }
 
+impl PixelLine {
+    /// Run-length encoded line: borsh serialized `Vec<(u8, Pixel)>` of
+    /// (run length, pixel) pairs.
+    pub fn compress(&self) -> Vec<u8> {
+        let mut runs: Vec<(u8, Pixel)> = Vec::new();
+        for p in &self.0 {
+            match runs.last_mut() {
+                Some((n, last)) if last.color == p.color && last.owner_id == p.owner_id => *n += 1,
+                _ => runs.push((1, *p)),
+            }
+        }
+        runs.try_to_vec().unwrap()
+    }
+}
+
+/// Pixel data used for pricing. Kept outside of `Pixel` to not change the
+/// line format returned by `get_lines`.
+#[derive(BorshDeserialize, BorshSerialize, Copy, Clone, Default)]
+pub struct PixelMeta {
+    /// time (in nanoseconds) when the pixel was painted the last time
+    pub painted_at: u64,
+    /// number of times the pixel was taken over from another account
+    pub overwrites: u16,
+    /// price (in Milk) self-assessed by the owner, used in the Harberger tax mode
+    pub price: u32,
+    /// time (in nanoseconds) until the pixel can't be overwritten by other accounts
+    pub shielded_until: u64,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct PixelMetaLine(pub Vec<PixelMeta>);
+
+impl Default for PixelMetaLine {
+    fn default() -> Self {
+        Self(vec![PixelMeta::default(); BOARD_WIDTH as usize])
+    }
+}
+
+/// Parameters of the dynamic pixel price.
+/// Overwriting a pixel owned by another account costs
+/// `2^min(overwrites + 1, max_doublings)` Milk, halved for every `half_life`
+/// elapsed since the pixel was painted, but never less than 1 Milk.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Copy, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PixelPricing {
+    /// in nanoseconds. Zero disables the surcharge.
+    pub half_life: u64,
+    pub max_doublings: u8,
+}
+
+impl PixelPricing {
+    pub fn pixel_cost(&self, meta: &PixelMeta, now: u64) -> Balance {
+        if self.half_life == 0 || meta.painted_at == 0 {
+            return 1;
+        }
+        let doublings = std::cmp::min(
+            u32::from(meta.overwrites) + 1,
+            u32::from(self.max_doublings),
+        );
+        let halvings = now.saturating_sub(meta.painted_at) / self.half_life;
+        if halvings >= u64::from(doublings) {
+            return 1;
+        }
+        1 << (u64::from(doublings) - halvings)
+    }
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Copy, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Rect {
+    pub x: u8,
+    pub y: u8,
+    pub width: u8,
+    pub height: u8,
+}
+
+impl Rect {
+    pub fn assert_valid(&self) {
+        assert!(self.width > 0 && self.height > 0, "Rect is empty");
+        assert!(
+            u16::from(self.x) + u16::from(self.width) <= u16::from(BOARD_WIDTH),
+            "X is out of bounds"
+        );
+        assert!(
+            u16::from(self.y) + u16::from(self.height) <= u16::from(BOARD_HEIGHT),
+            "Y is out of bounds"
+        );
+    }
+
+    pub fn area(&self) -> u32 {
+        u32::from(self.width) * u32::from(self.height)
+    }
+
+    pub fn contains(&self, x: u8, y: u8) -> bool {
+        x >= self.x
+            && y >= self.y
+            && u16::from(x) < u16::from(self.x) + u16::from(self.width)
+            && u16::from(y) < u16::from(self.y) + u16::from(self.height)
+    }
+
+    pub fn overlaps(&self, other: &Rect) -> bool {
+        let (x, y) = (u16::from(self.x), u16::from(self.y));
+        let (ox, oy) = (u16::from(other.x), u16::from(other.y));
+        x < ox + u16::from(other.width)
+            && ox < x + u16::from(self.width)
+            && y < oy + u16::from(other.height)
+            && oy < y + u16::from(self.height)
+    }
+}
+
+/// Protection of the owner's pixels in `rect` bought with `PixelBoard::protect`.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Shield {
+    pub owner_id: AccountIndex,
+    pub rect: Rect,
+    /// expiration time in nanoseconds
+    pub expires_at: u64,
+}
+
 #[derive(BorshDeserialize, BorshSerialize)]
 pub struct PixelBoard {
     pub lines: Vector<PixelLine>,
+    pub metas: Vector<PixelMetaLine>,
     pub line_versions: Vec<u32>,
+    pub shields: Vec<Shield>,
+    /// number of pixels per color bucket, see `color_bucket`
+    pub color_buckets: Vec<u32>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BoardStats {
+    /// number of pixels per color bucket. Bucket index is `r << 4 | g << 2 | b`
+    /// where r, g, b are the 2 most significant bits of the color channels.
+    pub color_buckets: Vec<u32>,
+    /// number of accounts owning at least one pixel (the contract excluded)
+    pub num_owners: u32,
+    /// pixels owned by the contract account (never painted or foreclosed)
+    pub contract_pixels: u32,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -47,6 +200,33 @@ pub struct SetPixelRequest {
     pub x: u8,
     pub y: u8,
     pub color: u32,
+    /// self-assessed price of the pixel (Harberger tax mode only)
+    pub price: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SetPixelPriceRequest {
+    pub x: u8,
+    pub y: u8,
+    pub price: u32,
+}
+
+/// Pixel state before a draw, used to undo it.
+#[derive(BorshDeserialize, BorshSerialize, Clone, Copy)]
+pub struct UndoPixel {
+    pub x: u8,
+    pub y: u8,
+    pub pixel: Pixel,
+    pub painted_at: u64,
+}
+
+/// Pixels of one owner replaced by `PixelBoard::set_pixels`.
+#[derive(Default)]
+pub struct ReplacedPixels {
+    pub num_pixels: u32,
+    /// sum of the declared prices of the replaced pixels
+    pub value: Balance,
 }
 
 impl SetPixelRequest {
@@ -61,11 +241,16 @@ impl PixelBoard {
     pub fn new() -> Self {
         let mut board = Self {
             lines: Vector::new(b"p".to_vec()),
+            metas: Vector::new(b"m".to_vec()),
             line_versions: vec![0; BOARD_HEIGHT as usize],
+            shields: Vec::new(),
+            color_buckets: initial_color_buckets(),
         };
         let default_line = PixelLine::default();
+        let default_meta = PixelMetaLine::default();
         for _ in 0..BOARD_HEIGHT {
             board.lines.push(&default_line);
+            board.metas.push(&default_meta);
         }
         board
     }
@@ -74,35 +259,239 @@ impl PixelBoard {
         self.lines.get(u64::from(index)).unwrap()
     }
 
-    /// Returns the list of the old owner IDs for the replaced pixels
+    /// Returns the cost (in Milk) of drawing `pixels` by `new_owner_id`.
+    /// Every pixel is priced against the current board state. When
+    /// `with_prices` is set, the declared prices of the pixels taken over are
+    /// included.
+    pub fn quote(
+        &self,
+        new_owner_id: AccountIndex,
+        pixels: &[SetPixelRequest],
+        pricing: &PixelPricing,
+        with_prices: bool,
+    ) -> Balance {
+        let now = env::block_timestamp();
+        let mut lines = HashMap::new();
+        let mut cost = 0;
+        for request in pixels {
+            request.assert_valid();
+            let (line, meta) = lines.entry(request.y).or_insert_with(|| {
+                (
+                    self.get_line(u32::from(request.y)),
+                    self.get_meta_line(u32::from(request.y)),
+                )
+            });
+            let x = request.x as usize;
+            cost += if line.0[x].owner_id == new_owner_id {
+                1
+            } else if with_prices {
+                pricing.pixel_cost(&meta.0[x], now) + Balance::from(meta.0[x].price)
+            } else {
+                pricing.pixel_cost(&meta.0[x], now)
+            };
+        }
+        cost
+    }
+
+    /// Returns the list of the old owner IDs for the replaced pixels and the
+    /// old state of the requested pixels.
+    /// Shielded pixels can only be changed by their owner (`painter_id`).
+    /// Pixel prices from the requests are only set when `with_prices` is true.
     pub fn set_pixels(
         &mut self,
-        new_owner_id: u32,
+        painter_id: AccountIndex,
+        new_owner_id: AccountIndex,
         pixels: &[SetPixelRequest],
-    ) -> HashMap<AccountIndex, u32> {
+        with_prices: bool,
+    ) -> (HashMap<AccountIndex, ReplacedPixels>, Vec<UndoPixel>) {
+        let now = env::block_timestamp();
         let mut lines = HashMap::new();
         let mut old_owners = HashMap::new();
+        let mut old_pixels = Vec::with_capacity(pixels.len());
         for request in pixels {
             request.assert_valid();
-            let line = lines
-                .entry(request.y)
-                .or_insert_with(|| self.lines.get(u64::from(request.y)).unwrap());
-            let old_owner = line.0[request.x as usize].owner_id;
-            line.0[request.x as usize] = Pixel {
+            let (line, meta) = lines.entry(request.y).or_insert_with(|| {
+                (
+                    self.lines.get(u64::from(request.y)).unwrap(),
+                    self.metas.get(u64::from(request.y)).unwrap(),
+                )
+            });
+            let x = request.x as usize;
+            let old_owner = line.0[x].owner_id;
+            let meta = &mut meta.0[x];
+            assert!(
+                old_owner == painter_id || meta.shielded_until <= now,
+                "Pixel is protected by a shield"
+            );
+            old_pixels.push(UndoPixel {
+                x: request.x,
+                y: request.y,
+                pixel: line.0[x],
+                painted_at: meta.painted_at,
+            });
+            self.color_buckets[color_bucket(line.0[x].color)] -= 1;
+            self.color_buckets[color_bucket(request.color)] += 1;
+            line.0[x] = Pixel {
                 owner_id: new_owner_id,
                 color: request.color,
             };
-            *old_owners.entry(old_owner).or_default() += 1;
+            if old_owner != new_owner_id && meta.painted_at != 0 {
+                meta.overwrites = meta.overwrites.saturating_add(1);
+            }
+            meta.painted_at = now;
+            let replaced: &mut ReplacedPixels = old_owners.entry(old_owner).or_default();
+            replaced.num_pixels += 1;
+            replaced.value += Balance::from(meta.price);
+            meta.price = if with_prices {
+                request.price.unwrap_or(0)
+            } else {
+                0
+            };
+        }
+        for (i, (line, meta)) in lines {
+            self.save_line(i, &line, &meta);
+        }
+
+        (old_owners, old_pixels)
+    }
+
+    /// Restores pixels painted by `owner_id` at `painted_at` (in reverse
+    /// order), skipping the ones changed since. Returns the restored pixels
+    /// with the colors they had before the undo.
+    pub fn undo_pixels(
+        &mut self,
+        owner_id: AccountIndex,
+        painted_at: u64,
+        pixels: &[UndoPixel],
+    ) -> Vec<(UndoPixel, u32)> {
+        let mut lines = HashMap::new();
+        let mut restored = Vec::new();
+        for old in pixels.iter().rev() {
+            let (line, meta) = lines.entry(old.y).or_insert_with(|| {
+                (
+                    self.lines.get(u64::from(old.y)).unwrap(),
+                    self.metas.get(u64::from(old.y)).unwrap(),
+                )
+            });
+            let x = old.x as usize;
+            let meta = &mut meta.0[x];
+            if line.0[x].owner_id != owner_id || meta.painted_at != painted_at {
+                continue;
+            }
+            let color = line.0[x].color;
+            self.color_buckets[color_bucket(color)] -= 1;
+            self.color_buckets[color_bucket(old.pixel.color)] += 1;
+            line.0[x] = old.pixel;
+            if old.pixel.owner_id != owner_id && old.painted_at != 0 {
+                meta.overwrites = meta.overwrites.saturating_sub(1);
+            }
+            meta.painted_at = old.painted_at;
+            restored.push((*old, color));
+        }
+        for (i, (line, meta)) in lines {
+            self.save_line(i, &line, &meta);
+        }
+        restored
+    }
+
+    /// Updates prices of the pixels owned by `owner_id`.
+    /// Returns the sum of old and new prices.
+    pub fn set_prices(
+        &mut self,
+        owner_id: AccountIndex,
+        pixels: &[SetPixelPriceRequest],
+    ) -> (Balance, Balance) {
+        let mut metas = HashMap::new();
+        let (mut old_value, mut new_value) = (0, 0);
+        for request in pixels {
+            assert!(request.x < BOARD_WIDTH, "X is out of bounds");
+            assert!(request.y < BOARD_HEIGHT, "Y is out of bounds");
+            let pixel = self.get_line(u32::from(request.y)).0[request.x as usize];
+            assert!(
+                pixel.owner_id == owner_id,
+                "Pixel is not owned by the account"
+            );
+            let meta = metas
+                .entry(request.y)
+                .or_insert_with(|| self.get_meta_line(u32::from(request.y)));
+            let meta = &mut meta.0[request.x as usize];
+            old_value += Balance::from(meta.price);
+            new_value += Balance::from(request.price);
+            meta.price = request.price;
         }
-        for (i, line) in lines {
-            self.save_line(i, &line);
+        for (i, meta) in metas {
+            self.metas.replace(u64::from(i), &meta);
         }
+        (old_value, new_value)
+    }
 
-        old_owners
+    /// Transfers all pixels of `owner_id` to the contract account (index 0)
+    /// and clears their prices. Returns number of pixels and their value.
+    pub fn foreclose(&mut self, owner_id: AccountIndex) -> (u32, Balance) {
+        let (mut num_pixels, mut value) = (0, 0);
+        for i in 0..BOARD_HEIGHT {
+            let mut line = self.get_line(u32::from(i));
+            if !line.0.iter().any(|p| p.owner_id == owner_id) {
+                continue;
+            }
+            let mut meta = self.get_meta_line(u32::from(i));
+            for (pixel, meta) in line.0.iter_mut().zip(meta.0.iter_mut()) {
+                if pixel.owner_id == owner_id {
+                    pixel.owner_id = 0;
+                    num_pixels += 1;
+                    value += Balance::from(meta.price);
+                    meta.price = 0;
+                    meta.shielded_until = 0;
+                }
+            }
+            self.save_line(i, &line, &meta);
+        }
+        (num_pixels, value)
     }
 
-    fn save_line(&mut self, index: u8, line: &PixelLine) {
+    /// Protects pixels in `rect` owned by `owner_id` from being overwritten
+    /// by other accounts until `expires_at`. Returns number of protected pixels.
+    pub fn protect(&mut self, owner_id: AccountIndex, rect: Rect, expires_at: u64) -> u32 {
+        rect.assert_valid();
+        let now = env::block_timestamp();
+        self.shields.retain(|s| s.expires_at > now);
+        assert!(
+            self.shields
+                .iter()
+                .filter(|s| s.owner_id == owner_id)
+                .count()
+                < MAX_ACTIVE_SHIELDS,
+            "Too many active shields"
+        );
+
+        let mut num_pixels = 0;
+        for y in rect.y..rect.y + rect.height {
+            let line = self.get_line(u32::from(y));
+            let mut meta = self.get_meta_line(u32::from(y));
+            for x in rect.x as usize..(rect.x + rect.width) as usize {
+                if line.0[x].owner_id == owner_id {
+                    let m = &mut meta.0[x];
+                    m.shielded_until = std::cmp::max(m.shielded_until, expires_at);
+                    num_pixels += 1;
+                }
+            }
+            self.metas.replace(u64::from(y), &meta);
+        }
+        self.shields.push(Shield {
+            owner_id,
+            rect,
+            expires_at,
+        });
+        num_pixels
+    }
+
+    pub fn get_meta_line(&self, index: u32) -> PixelMetaLine {
+        self.metas.get(u64::from(index)).unwrap()
+    }
+
+    fn save_line(&mut self, index: u8, line: &PixelLine, meta: &PixelMetaLine) {
         self.lines.replace(u64::from(index), line);
+        self.metas.replace(u64::from(index), meta);
         self.line_versions[index as usize] += 1;
     }
 }
@@ -122,4 +511,50 @@ impl Place {
     pub fn get_line_versions(&self) -> Vec<u32> {
         self.board.line_versions.clone()
     }
+
+    pub fn board_stats(&self) -> BoardStats {
+        BoardStats {
+            color_buckets: self.board.color_buckets.clone(),
+            num_owners: self.num_pixel_owners,
+            contract_pixels: self.get_internal_account_by_index(0).unwrap().num_pixels,
+        }
+    }
+
+    /// Returns the amount of Milk the account would pay for drawing `pixels`.
+    pub fn quote_draw(&self, account_id: ValidAccountId, pixels: Vec<SetPixelRequest>) -> U128 {
+        let account_index = self
+            .account_indices
+            .get(account_id.as_ref())
+            .unwrap_or_else(|| self.next_account_index());
+        self.board
+            .quote(
+                account_index,
+                &pixels,
+                &self.pixel_pricing,
+                self.harberger_tax_rate > 0,
+            )
+            .into()
+    }
+
+    /// Returns active shields overlapping with `rect`.
+    pub fn get_shields(&self, rect: Rect) -> Vec<Shield> {
+        let now = env::block_timestamp();
+        self.board
+            .shields
+            .iter()
+            .filter(|s| s.expires_at > now && s.rect.overlaps(&rect))
+            .cloned()
+            .collect()
+    }
+
+    /// Returns self-assessed pixel prices (in Milk) of the requested lines.
+    pub fn get_pixel_prices(&self, lines: Vec<u32>) -> Vec<Vec<u32>> {
+        lines
+            .into_iter()
+            .map(|i| {
+                let meta = self.board.get_meta_line(i);
+                meta.0.iter().map(|m| m.price).collect()
+            })
+            .collect()
+    }
 }
@@ -5,7 +5,8 @@ use near_contract_standards::storage_management::{
 use std::convert::TryInto;
 use std::mem::size_of;
 
-const STORAGE_AMOUNT_BYTES: usize = size_of::<UpgradableAccount>() + 68 + 40 + 100;
+const STORAGE_AMOUNT_BYTES: usize =
+    size_of::<UpgradableAccount>() + 68 + 40 + 100 + 2 * SORTED_LEADERBOARD_ENTRY_BYTES;
 
 #[allow(unused_variables)]
 #[near_bindgen]
@@ -1,3 +1,6 @@
+use std::collections::HashSet;
+
+use crate::migrate::STATE_VERSION;
 use crate::*;
 
 pub(crate) fn assert_self() {
@@ -9,6 +12,97 @@ pub(crate) fn assert_self() {
 }
 
 impl Place {
+    /// Contract state with the default settings. `ends` is in nanoseconds.
+    pub(crate) fn init(
+        cheddar: AccountId,
+        admin: AccountId,
+        treasury: AccountId,
+        ends: u64,
+        board: PixelBoard,
+    ) -> Self {
+        // milk
+        let milk_price = ONE_NEAR / 400;
+        let milk_cheddar_price = milk_price * MILK_CHEDAR_FACTOR;
+        Self {
+            account_indices: LookupMap::new(b"i".to_vec()),
+            accounts: LookupMap::new(b"u".to_vec()),
+            num_accounts: 0,
+            free_account_indices: Vec::new(),
+            board,
+            last_reward_timestamp: env::block_timestamp(),
+            bought_milk: 0,
+            milk_bought_with_near: 0,
+            milk_bought_with_cheddar: 0,
+            used_milk: 0,
+            farmed_cheddar: 0,
+            withdrawn_cheddar: 0,
+            refunded_cheddar: 0,
+            deposited_cheddar: 0,
+            near_collected: 0,
+            treasury_withdrawn: 0,
+
+            is_active: true,
+            admin,
+            cheddar,
+            treasury,
+            mint_funded: 0,
+            // Initial reward is 1 cheddar per day per pixel.
+            // that is 80**2 = 6400 / day in total
+            //   updated: 0.25 cheddar / day == 2893518518 / ns
+            reward_rate: ONE_NEAR / (24 * 60 * 60 * u128::from(FROM_NANO)),
+            milk_price,
+            blacklist: LookupSet::new(b"b".to_vec()),
+            starts: 0, // placeholder for the moment
+            ends,
+            pixel_pricing: PixelPricing {
+                half_life: 60 * 60 * FROM_NANO,
+                max_doublings: 5,
+            },
+            harberger_tax_rate: 0,
+            // 100 pixels protected for one hour cost 1 Milk
+            shield_rate: 100 * 60 * 60,
+            milk_curve: None,
+            // 20% bonus for purchases of at least 5 NEAR
+            discount_tiers: vec![DiscountTier {
+                threshold: (5 * ONE_NEAR).into(),
+                bonus: 2_000,
+            }],
+            cheddar_discount_tiers: Vec::new(),
+            milk_cheddar_price,
+            milk_cheddar_price_bounds: (milk_cheddar_price / 10, milk_cheddar_price * 10),
+            price_oracle: None,
+            milk_cheddar_price_history: Vector::new(b"h".to_vec()),
+            payment_tokens: UnorderedMap::new(b"t".to_vec()),
+            storage_pool: 0,
+            pixels_leaderboard: SortedLeaderboard::new(b"P"),
+            farmed_leaderboard: Leaderboard::default(),
+            milk_spent_leaderboard: SortedLeaderboard::new(b"M"),
+            daily_stats: Vector::new(b"d".to_vec()),
+            num_daily_stats: 0,
+            num_pixel_owners: 0,
+            teams: Vec::new(),
+            team_bonus: TeamBonus::default(),
+            season: 0,
+            seasons: LookupMap::new(b"s".to_vec()),
+            season_lines: LookupMap::new(b"l".to_vec()),
+            season_prize: 0,
+            prize_funds: 0,
+            paid_prizes: 0,
+            season_settlement: None,
+            templates: Vector::new(b"g".to_vec()),
+            active_templates: Vec::new(),
+            template_contributions: LookupMap::new(b"c".to_vec()),
+            template_pixel_contributors: LookupMap::new(b"n".to_vec()),
+            weapon_surcharge: 2_000,
+            draw_limits: DrawLimits::default(),
+            reveal_window: 10 * 60,
+            undo_window: 60,
+            undo_refund: 5_000,
+            undo_records: LookupMap::new(b"r".to_vec()),
+            state_version: STATE_VERSION,
+        }
+    }
+
     pub(crate) fn only_admin(&self) {
         assert!(env::predecessor_account_id() == self.admin, "Not an admin");
     }
@@ -19,6 +113,233 @@ impl Place {
         assert!(bt >= self.starts, "Game didn't started yet");
         assert!(bt <= self.ends, "Game is over");
     }
+
+    /// Sets the Milk price in Cheddar and records the change in the history.
+    pub(crate) fn internal_set_milk_cheddar_price(
+        &mut self,
+        price: Balance,
+        updated_by: AccountId,
+    ) {
+        self.milk_cheddar_price = price;
+        self.milk_cheddar_price_history.push(&PriceChange {
+            price: price.into(),
+            timestamp: env::block_timestamp() / FROM_NANO,
+            updated_by,
+        });
+    }
+
+    pub(crate) fn internal_buy_milk_with_cheddar(
+        &mut self,
+        account_id: &AccountId,
+        cheddar: Balance,
+    ) {
+        let mut account = self.get_mut_account(account_id);
+        let x = account.buy_milk_with_cheddar(
+            cheddar,
+            self.milk_cheddar_price,
+            &self.cheddar_discount_tiers,
+        );
+        self.save_account(account);
+        self.bought_milk += x;
+        self.milk_bought_with_cheddar += x;
+        self.record_daily(|s| s.milk_bought = (s.milk_bought.0 + x).into());
+    }
+
+    /// Buys Milk with a whitelisted payment token. Returns the unused amount.
+    pub(crate) fn internal_buy_milk_with_token(
+        &mut self,
+        account_id: &AccountId,
+        token_id: &AccountId,
+        amount: Balance,
+    ) -> Balance {
+        let mut token = self
+            .payment_tokens
+            .get(token_id)
+            .expect("Token is not accepted as payment");
+        let paid = amount - amount % token.milk_price.0;
+        if paid == 0 {
+            return amount;
+        }
+        let mut account = self.get_mut_account(account_id);
+        let x = account.buy_milk_with_token(
+            paid,
+            token_id,
+            &token,
+            self.milk_price,
+            &self.discount_tiers,
+        );
+        self.save_account(account);
+        self.bought_milk += x;
+        self.record_daily(|s| s.milk_bought = (s.milk_bought.0 + x).into());
+        token.received = (token.received.0 + paid).into();
+        token.bought_milk = (token.bought_milk.0 + x).into();
+        self.payment_tokens.insert(token_id, &token);
+        amount - paid
+    }
+
+    /// Restores the empty board: all pixels go back to the contract account.
+    /// Pixel owners are touched first, so farming and taxes are settled on
+    /// the current board.
+    pub(crate) fn clear_board(&mut self) {
+        let mut users = HashSet::new();
+        for i in 0..BOARD_HEIGHT {
+            for pl in self.board.lines.get(i.into()) {
+                for p in pl.0 {
+                    users.insert(p.owner_id);
+                }
+            }
+        }
+        users.remove(&0);
+        for u_idx in users {
+            let mut a = self.get_internal_account_by_index(u_idx).unwrap();
+            self.touch(&mut a);
+            a.num_pixels = 0;
+            a.declared_value = 0;
+            a.accrued_tax = 0;
+            self.save_account(a);
+        }
+        self.reset_board_lines();
+    }
+
+    /// Resets the board lines and stats and gives all pixels to the contract
+    /// account. The owners' pixel counts must be reset by the caller.
+    pub(crate) fn reset_board_lines(&mut self) {
+        let default_line = PixelLine::default();
+        let default_meta = PixelMetaLine::default();
+        let changes = self.clear_templates_changes();
+        self.board.shields.clear();
+        self.board.color_buckets = initial_color_buckets();
+        self.num_pixel_owners = 0;
+        for t in self.teams.iter_mut() {
+            t.territory = 0;
+        }
+        for i in 0..BOARD_HEIGHT {
+            self.board.lines.replace(i.into(), &default_line);
+            self.board.line_versions[i as usize] += 1;
+            self.board.metas.replace(i.into(), &default_meta);
+        }
+
+        let mut a = self.get_internal_account_by_index(0).unwrap();
+        self.touch(&mut a);
+        a.num_pixels = TOTAL_NUM_PIXELS;
+        self.save_account(a);
+        self.track_templates(&changes);
+    }
+
+    pub(crate) fn internal_draw(&mut self, account_id: &AccountId, pixels: &[SetPixelRequest]) {
+        self.internal_paint(account_id, pixels, false);
+    }
+
+    /// Paints the pixels white and gives them back to the contract account.
+    /// The account pays the same as for drawing them.
+    pub(crate) fn internal_clear(&mut self, account_id: &AccountId, pixels: &[SetPixelRequest]) {
+        self.internal_paint(account_id, pixels, true);
+    }
+
+    fn internal_paint(&mut self, account_id: &AccountId, pixels: &[SetPixelRequest], clear: bool) {
+        let mut account = self.get_mut_account(account_id);
+        let new_pixels = pixels.len() as u32;
+        account.draws.record(&self.draw_limits, new_pixels);
+        let cost = self
+            .board
+            .quote(account.account_index, pixels, &self.pixel_pricing, false);
+        account.charge(Berry::Milk, cost);
+        self.used_milk += cost;
+        self.record_daily(|s| {
+            s.pixels_drawn += u64::from(new_pixels);
+            s.milk_used = (s.milk_used.0 + cost).into();
+        });
+
+        let harberger = self.harberger_tax_rate > 0;
+        let with_prices = harberger && !clear;
+        let new_owner_id = if clear { 0 } else { account.account_index };
+        let (mut old_owners, old_pixels) =
+            self.board
+                .set_pixels(account.account_index, new_owner_id, pixels, with_prices);
+        let painter = (!clear).then(|| account.account_id.clone());
+        let changes: Vec<PixelChange> = pixels
+            .iter()
+            .zip(&old_pixels)
+            .map(|(p, old)| PixelChange {
+                x: p.x,
+                y: p.y,
+                old_color: old.pixel.color,
+                color: p.color,
+                painter: painter.clone(),
+            })
+            .collect();
+        self.track_templates(&changes);
+        if !with_prices && !clear && self.undo_window > 0 && old_pixels.len() <= MAX_UNDO_PIXELS {
+            let owners = old_owners
+                .keys()
+                .filter(|i| **i != 0 && **i != account.account_index)
+                .map(|i| (*i, self.get_account_id_by_index(*i).unwrap()))
+                .collect();
+            let record = UndoRecord {
+                drawn_at: env::block_timestamp(),
+                milk: cost,
+                pixels: old_pixels,
+                owners,
+            };
+            self.undo_records.insert(&account.account_id, &record);
+        } else {
+            self.undo_records.remove(&account.account_id);
+        }
+        let replaced = old_owners
+            .remove(&account.account_index)
+            .unwrap_or_default();
+        let old_num_pixels = account.num_pixels;
+        if !clear {
+            account.num_pixels += new_pixels;
+        }
+        account.num_pixels -= replaced.num_pixels;
+        self.update_pixel_counts(&account, old_num_pixels);
+        account.stats.pixels_painted += u64::from(new_pixels);
+        account.declared_value -= replaced.value;
+        if with_prices {
+            account.declared_value += pixels
+                .iter()
+                .map(|p| Balance::from(p.price.unwrap_or(0)))
+                .sum::<Balance>();
+        }
+        if harberger {
+            // taking over pixels requires paying the declared price to their owners
+            let takeover_cost = old_owners.values().map(|r| r.value).sum();
+            account.charge(Berry::Milk, takeover_cost);
+        }
+        self.save_account(account);
+
+        for (account_index, replaced) in old_owners {
+            let mut account = self.get_internal_account_by_index(account_index).unwrap();
+            self.touch(&mut account);
+            account.num_pixels -= replaced.num_pixels;
+            self.update_pixel_counts(&account, account.num_pixels + replaced.num_pixels);
+            account.stats.pixels_lost += u64::from(replaced.num_pixels);
+            account.declared_value -= replaced.value;
+            if harberger {
+                account.balances[Berry::Milk as usize] += replaced.value;
+            }
+            self.save_account(account);
+        }
+        if clear {
+            let mut a = self.get_internal_account_by_index(0).unwrap();
+            a.num_pixels += new_pixels;
+            self.save_account(a);
+        }
+    }
+}
+
+/// NEP-141 token accepted as a payment for Milk.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PaymentToken {
+    /// price of one Milk in the token units
+    pub milk_price: U128,
+    pub decimals: u8,
+    /// total amount of tokens received
+    pub received: U128,
+    /// total amount of Milk bought with the token
+    pub bought_milk: U128,
 }
 
 #[derive(Serialize)]
@@ -32,4 +353,21 @@ pub struct Settings {
     pub start_date: u64,
     /// end timestamp in seconds
     pub end_date: u64,
+    /// pixel price surcharge half life in seconds
+    pub pixel_price_half_life: u64,
+    pub max_pixel_price_doublings: u8,
+    /// Harberger tax in basis points of the declared value per day
+    pub harberger_tax_rate: u32,
+    /// pixel-seconds of shield protection per one Milk
+    pub shield_rate: u64,
+    /// bonding curve for Milk sold for NEAR (if set, `milk_price` is not used)
+    pub milk_curve: Option<MilkCurve>,
+    /// bonus tiers for Milk bought with NEAR
+    pub discount_tiers: Vec<DiscountTier>,
+    /// bonus tiers for Milk bought with Cheddar
+    pub cheddar_discount_tiers: Vec<DiscountTier>,
+    /// milk token price in Cheddar
+    pub milk_cheddar_price: U128,
+    /// area weapons surcharge in basis points of the pixels cost
+    pub weapon_surcharge: u32,
 }
@@ -0,0 +1,144 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::{LookupMap, TreeMap};
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+
+use crate::*;
+
+pub const LEADERBOARD_SIZE: usize = 20;
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(crate = "near_sdk::serde")]
+pub enum LeaderboardKind {
+    Pixels,
+    FarmedCheddar,
+    MilkSpent,
+}
+
+/// Storage used by one account in a `SortedLeaderboard`: the score record and
+/// the tree node and value records, with the storage overhead of a record.
+pub(crate) const SORTED_LEADERBOARD_ENTRY_BYTES: usize = 230;
+
+/// Top accounts sorted by score (descending), updated whenever an account is
+/// saved. Only used for scores which never decrease: an account outside of
+/// the list can't get ahead of a listed one without being updated.
+#[derive(BorshDeserialize, BorshSerialize, Default)]
+pub struct Leaderboard(pub Vec<(AccountIndex, Balance)>);
+
+/// All accounts with a positive score, sorted by score. Used for scores which
+/// can decrease (eg pixels lost to other accounts), so an account below the
+/// top moves up when a listed account's score drops.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct SortedLeaderboard {
+    scores: LookupMap<AccountIndex, Balance>,
+    index: TreeMap<(Balance, AccountIndex), ()>,
+}
+
+impl SortedLeaderboard {
+    pub fn new(prefix: &[u8]) -> Self {
+        Self {
+            scores: LookupMap::new([prefix, b"s"].concat()),
+            index: TreeMap::new([prefix, b"i"].concat()),
+        }
+    }
+
+    pub fn update(&mut self, account_index: AccountIndex, score: Balance) {
+        let old = self.scores.get(&account_index).unwrap_or(0);
+        if old == score {
+            return;
+        }
+        if old > 0 {
+            self.index.remove(&(old, account_index));
+        }
+        if score > 0 {
+            self.index.insert(&(score, account_index), &());
+            self.scores.insert(&account_index, &score);
+        } else {
+            self.scores.remove(&account_index);
+        }
+    }
+
+    pub fn remove(&mut self, account_index: AccountIndex) {
+        self.update(account_index, 0);
+    }
+
+    /// Returns the `LEADERBOARD_SIZE` accounts with the highest score.
+    pub fn top(&self) -> Vec<(AccountIndex, Balance)> {
+        self.index
+            .iter_rev()
+            .take(LEADERBOARD_SIZE)
+            .map(|((score, account_index), _)| (account_index, score))
+            .collect()
+    }
+}
+
+impl Leaderboard {
+    pub fn update(&mut self, account_index: AccountIndex, score: Balance) {
+        self.remove(account_index);
+        if score == 0 {
+            return;
+        }
+        let pos = self
+            .0
+            .iter()
+            .position(|(_, s)| *s < score)
+            .unwrap_or(self.0.len());
+        if pos < LEADERBOARD_SIZE {
+            self.0.insert(pos, (account_index, score));
+            self.0.truncate(LEADERBOARD_SIZE);
+        }
+    }
+
+    pub fn remove(&mut self, account_index: AccountIndex) {
+        self.0.retain(|(i, _)| *i != account_index);
+    }
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct LeaderboardEntry {
+    pub account_id: AccountId,
+    pub account_index: AccountIndex,
+    pub score: U128,
+}
+
+impl Place {
+    pub(crate) fn update_leaderboards(&mut self, account: &Account) {
+        // the contract account holds the unpainted pixels
+        if account.account_index == 0 {
+            return;
+        }
+        let i = account.account_index;
+        self.pixels_leaderboard
+            .update(i, Balance::from(account.num_pixels));
+        self.farmed_leaderboard
+            .update(i, account.stats.farmed_cheddar);
+        self.milk_spent_leaderboard
+            .update(i, account.stats.milk_spent);
+    }
+
+    pub(crate) fn remove_from_leaderboards(&mut self, account_index: AccountIndex) {
+        self.pixels_leaderboard.remove(account_index);
+        self.farmed_leaderboard.remove(account_index);
+        self.milk_spent_leaderboard.remove(account_index);
+    }
+}
+
+#[near_bindgen]
+impl Place {
+    pub fn get_leaderboard(&self, kind: LeaderboardKind) -> Vec<LeaderboardEntry> {
+        let leaderboard = match kind {
+            LeaderboardKind::Pixels => self.pixels_leaderboard.top(),
+            LeaderboardKind::FarmedCheddar => self.farmed_leaderboard.0.clone(),
+            LeaderboardKind::MilkSpent => self.milk_spent_leaderboard.top(),
+        };
+        leaderboard
+            .into_iter()
+            .map(|(account_index, score)| LeaderboardEntry {
+                account_id: self.get_account_id_by_index(account_index).unwrap(),
+                account_index,
+                score: score.into(),
+            })
+            .collect()
+    }
+}
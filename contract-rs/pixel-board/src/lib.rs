@@ -1,7 +1,6 @@
-use std::collections::HashSet;
-
+use near_contract_standards::storage_management::StorageManagement;
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::collections::{LookupMap, LookupSet};
+use near_sdk::collections::{LookupMap, LookupSet, UnorderedMap, Vector};
 use near_sdk::json_types::{ValidAccountId, U128};
 use near_sdk::serde::{Deserialize, Serialize};
 use near_sdk::{env, log, near_bindgen, AccountId, Balance, Gas, Promise};
@@ -13,9 +12,14 @@ const STORAGE_PRICE_PER_BYTE: Balance = 100_000_000_000_000_000_000;
 /// Basic compute.
 pub(crate) const GAS_FOR_FT_MINT: Gas = 8_000_000_000_000;
 const GAS_FOR_RESOLVE_MINT: Gas = 5_000_000_000_000;
+const GAS_FOR_FT_TRANSFER: Gas = 10_000_000_000_000;
+const GAS_FOR_RESOLVE_TRANSFER: Gas = 5_000_000_000_000;
 const NO_DEPOSIT: Balance = 0;
 const SAFETY_BAR: Balance = 30 * ONE_NEAR;
+/// initial Milk price in Cheddar relative to the Milk price in NEAR
 const MILK_CHEDAR_FACTOR: Balance = 160;
+/// max decimals of a payment token, `10^decimals` must fit into a Balance
+const MAX_TOKEN_DECIMALS: u8 = 24;
 
 pub mod account;
 pub use crate::account::*;
@@ -30,7 +34,27 @@ mod minter;
 use crate::internal::*;
 
 mod internal;
+pub mod leaderboard;
+pub use crate::leaderboard::*;
+pub mod limits;
+pub use crate::limits::*;
+mod migrate;
+pub mod milk_curve;
+pub use crate::milk_curve::*;
+pub mod reveal;
+pub use crate::reveal::*;
+pub mod season;
+pub use crate::season::*;
 pub mod stats;
+pub use crate::stats::*;
+pub mod teams;
+pub use crate::teams::*;
+pub mod template;
+pub use crate::template::*;
+pub mod undo;
+pub use crate::undo::*;
+pub mod weapons;
+pub use crate::weapons::*;
 
 #[global_allocator]
 static ALLOC: near_sdk::wee_alloc::WeeAlloc<'_> = near_sdk::wee_alloc::WeeAlloc::INIT;
@@ -47,12 +71,28 @@ pub enum Berry {
 pub struct Place {
     pub account_indices: LookupMap<AccountId, u32>,
     pub accounts: LookupMap<u32, UpgradableAccount>,
+    /// number of allocated account indices (including the freed ones)
     pub num_accounts: u32,
+    /// indices of deleted accounts, reused by new accounts
+    pub free_account_indices: Vec<AccountIndex>,
     pub board: board::PixelBoard,
     pub last_reward_timestamp: u64,
-    pub bought_balances: Vec<Balance>,
+    /// total amount of Milk bought (with NEAR, Cheddar and payment tokens)
+    pub bought_milk: Balance,
+    pub milk_bought_with_near: Balance,
+    pub milk_bought_with_cheddar: Balance,
     pub used_milk: Balance, // we burn milk to farm cheddar
     pub farmed_cheddar: Balance,
+    /// farmed Cheddar minted to the accounts
+    pub withdrawn_cheddar: Balance,
+    /// Cheddar of the failed mints returned to the accounts
+    pub refunded_cheddar: Balance,
+    /// Cheddar deposited with `ft_transfer_call`
+    pub deposited_cheddar: Balance,
+    /// NEAR paid for Milk
+    pub near_collected: Balance,
+    /// NEAR transferred to the treasury with `withdraw_near`
+    pub treasury_withdrawn: Balance,
 
     pub is_active: bool,
     pub admin: AccountId,
@@ -70,6 +110,87 @@ pub struct Place {
     pub starts: u64,
     /// time when the game will finish in nanoseconds
     pub ends: u64,
+    /// surcharge for overwriting recently painted or contested pixels
+    pub pixel_pricing: PixelPricing,
+    /// Harberger tax in basis points of the declared pixel value per day.
+    /// Zero disables the Harberger tax mode.
+    pub harberger_tax_rate: u32,
+    /// number of pixel-seconds of shield protection bought with one Milk.
+    /// Zero disables shields.
+    pub shield_rate: u64,
+    /// when set, Milk bought with NEAR is priced by the curve instead of `milk_price`
+    pub milk_curve: Option<MilkCurve>,
+    /// bonus tiers for Milk bought with NEAR, sorted by threshold
+    pub discount_tiers: Vec<DiscountTier>,
+    /// bonus tiers for Milk bought with Cheddar, sorted by threshold
+    pub cheddar_discount_tiers: Vec<DiscountTier>,
+    /// price of one Milk in Cheddar
+    pub milk_cheddar_price: Balance,
+    /// `milk_cheddar_price` can only be set within [min, max]
+    pub milk_cheddar_price_bounds: (Balance, Balance),
+    /// account (e.g. a price oracle) allowed to update `milk_cheddar_price`
+    /// in addition to the admin
+    pub price_oracle: Option<AccountId>,
+    pub milk_cheddar_price_history: Vector<PriceChange>,
+    /// NEP-141 tokens (other than Cheddar) accepted as a payment for Milk
+    pub payment_tokens: UnorderedMap<AccountId, PaymentToken>,
+    /// NEAR reserved for registering accounts which deposit tokens before
+    /// registration. It's not withdrawn to the treasury.
+    pub storage_pool: Balance,
+    pub pixels_leaderboard: SortedLeaderboard,
+    pub farmed_leaderboard: Leaderboard,
+    /// Milk spent decreases when a draw is undone
+    pub milk_spent_leaderboard: SortedLeaderboard,
+    /// ring buffer of the last `MAX_DAILY_STATS` daily buckets
+    pub daily_stats: Vector<DailyStats>,
+    /// number of daily buckets created so far
+    pub num_daily_stats: u64,
+    /// number of accounts (other than the contract) with `num_pixels > 0`
+    pub num_pixel_owners: u32,
+    pub teams: Vec<Team>,
+    pub team_bonus: TeamBonus,
+    /// id of the current season
+    pub season: u32,
+    pub seasons: LookupMap<u32, Season>,
+    /// run-length encoded lines of the archived boards
+    pub season_lines: LookupMap<u64, Vec<u8>>,
+    /// Cheddar prize of the current season
+    pub season_prize: Balance,
+    /// Cheddar received for the season prizes and the team bonuses, not paid yet
+    pub prize_funds: Balance,
+    /// season prizes and team bonuses paid to the accounts
+    pub paid_prizes: Balance,
+    /// progress of `settle_season`, reset when the season is closed
+    pub season_settlement: Option<SeasonSettlement>,
+    pub templates: Vector<Template>,
+    /// templates which are neither completed nor expired, with their rects
+    pub active_templates: Vec<(TemplateId, Rect)>,
+    /// number of matching template pixels credited to the account
+    pub template_contributions: LookupMap<(TemplateId, AccountId), u32>,
+    /// account credited for a matching template pixel: (template, pixel index)
+    pub template_pixel_contributors: LookupMap<(TemplateId, u32), AccountId>,
+    /// Milk surcharge of area weapons, in basis points of the pixels cost
+    pub weapon_surcharge: u32,
+    pub draw_limits: DrawLimits,
+    /// time (in seconds) to reveal a committed draw
+    pub reveal_window: u64,
+    /// time (in seconds) to undo the last draw, zero disables undo
+    pub undo_window: u64,
+    /// part of the Milk refunded on undo, in basis points
+    pub undo_refund: u32,
+    /// last draw of the accounts, replaced on every draw
+    pub undo_records: LookupMap<AccountId, UndoRecord>,
+    /// version of the state layout, see `STATE_VERSION`
+    pub state_version: u32,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PriceChange {
+    pub price: U128,
+    /// timestamp in seconds
+    pub timestamp: u64,
+    pub updated_by: AccountId,
 }
 
 impl Default for Place {
@@ -89,37 +210,20 @@ impl Place {
         ends: u64,
     ) -> Self {
         assert!(!env::state_exists(), "Already initialized");
-        // milk
-        let milk_price = ONE_NEAR / 400;
-        let mut place = Self {
-            account_indices: LookupMap::new(b"i".to_vec()),
-            accounts: LookupMap::new(b"u".to_vec()),
-            num_accounts: 0,
-            board: PixelBoard::new(),
-            last_reward_timestamp: env::block_timestamp(),
-            bought_balances: vec![0, 0],
-            used_milk: 0,
-            farmed_cheddar: 0,
-
-            is_active: true,
-            admin: admin.into(),
-            cheddar: cheddar.into(),
-            treasury: treasury.into(),
-            mint_funded: 0,
-            // Initial reward is 1 cheddar per day per pixel.
-            // that is 80**2 = 6400 / day in total
-            //   updated: 0.25 cheddar / day == 2893518518 / ns
-            reward_rate: ONE_NEAR / (24 * 60 * 60 * u128::from(FROM_NANO)),
-            milk_price,
-            blacklist: LookupSet::new(b"b".to_vec()),
-            starts: 0, // placeholder for the moment
-            ends: ends * FROM_NANO,
-        };
+        let mut place = Self::init(
+            cheddar.into(),
+            admin.into(),
+            treasury.into(),
+            ends * FROM_NANO,
+            PixelBoard::new(),
+        );
 
         // use a good account
         let mut account = Account::new(env::current_account_id(), 0);
         account.num_pixels = TOTAL_NUM_PIXELS;
         place.save_account(account);
+        let admin = place.admin.clone();
+        place.internal_set_milk_cheddar_price(place.milk_cheddar_price, admin);
 
         place
     }
@@ -130,6 +234,15 @@ impl Place {
             milk_price: self.milk_price.into(),
             start_date: self.starts / FROM_NANO,
             end_date: self.ends / FROM_NANO,
+            pixel_price_half_life: self.pixel_pricing.half_life / FROM_NANO,
+            max_pixel_price_doublings: self.pixel_pricing.max_doublings,
+            harberger_tax_rate: self.harberger_tax_rate,
+            shield_rate: self.shield_rate,
+            milk_curve: self.milk_curve.clone(),
+            discount_tiers: self.discount_tiers.clone(),
+            cheddar_discount_tiers: self.cheddar_discount_tiers.clone(),
+            milk_cheddar_price: self.milk_cheddar_price.into(),
+            weapon_surcharge: self.weapon_surcharge,
         }
     }
 
@@ -143,23 +256,59 @@ impl Place {
         self.account_indices.contains_key(account_id.as_ref())
     }
 
-    /// buy milk tokens with NEAR
+    /// Adds the attached deposit to the pool paying the storage of accounts
+    /// registered without `storage_deposit`. Anyone can fund it.
+    #[payable]
+    pub fn fund_storage_pool(&mut self) {
+        assert!(
+            env::attached_deposit() > 0,
+            "Attach a deposit to fund the pool"
+        );
+        self.storage_pool += env::attached_deposit();
+    }
+
+    pub fn get_storage_pool(&self) -> U128 {
+        self.storage_pool.into()
+    }
+
+    /// buy milk tokens with NEAR. The storage of a new account is paid from
+    /// the attached deposit. NEAR not spent on Milk is refunded.
     #[payable]
     pub fn buy_tokens(&mut self) {
         self.assert_active();
         let account_id = env::predecessor_account_id();
         assert!(!self.blacklist.contains(&account_id), "Account blacklisted");
 
-        let near_amount = env::attached_deposit();
+        let mut near_amount = env::attached_deposit();
         assert!(
             near_amount >= ONE_NEAR / 10,
             "Min 0.1 NEAR payment is required"
         );
+        if !self.account_indices.contains_key(&account_id) {
+            near_amount -= self.storage_balance_bounds().min.0;
+        }
 
+        let (milk, cost) = self.milk_for_near(near_amount);
         let mut account = self.get_mut_account(&account_id);
-        let a = account.buy_tokens(near_amount, self.milk_price);
+        let a = account.buy_tokens(cost, milk, &self.discount_tiers);
         self.save_account(account);
-        self.bought_balances[Berry::Milk as usize] += a;
+        self.bought_milk += a;
+        self.milk_bought_with_near += a;
+        self.near_collected += cost;
+        self.record_daily(|s| {
+            s.milk_bought = (s.milk_bought.0 + a).into();
+            s.near_received = (s.near_received.0 + cost).into();
+        });
+        if near_amount > cost {
+            Promise::new(account_id).transfer(near_amount - cost);
+        }
+    }
+
+    /// Returns amount of Milk (including the discount) which would be bought
+    /// for `near_amount`.
+    pub fn quote_milk(&self, near_amount: U128) -> U128 {
+        let (amount, cost) = self.milk_for_near(near_amount.0);
+        apply_discount(&self.discount_tiers, cost, amount).0.into()
     }
 
     pub fn buy_milk_with_cheddar(&mut self, spent_cheddar: U128) {
@@ -167,12 +316,7 @@ impl Place {
         let account_id = env::predecessor_account_id();
         assert!(!self.blacklist.contains(&account_id), "Account blacklisted");
 
-        let mut account = self.get_mut_account(&account_id);
-        // TODO - should create a migration and put it into a state
-        let x = account
-            .buy_milk_with_cheddar(spent_cheddar.into(), self.milk_price * MILK_CHEDAR_FACTOR);
-        self.save_account(account);
-        self.bought_balances[Berry::Milk as usize] += x;
+        self.internal_buy_milk_with_cheddar(&account_id, spent_cheddar.into());
     }
 
     pub fn draw(&mut self, pixels: Vec<SetPixelRequest>) {
@@ -183,62 +327,154 @@ impl Place {
         if pixels.is_empty() {
             return;
         }
+        self.internal_draw(&account_id, &pixels);
+    }
+
+    /// Protects the predecessor's pixels in `rect` from being overwritten by
+    /// other accounts for `duration` seconds. Charges Milk proportionally to
+    /// the rect area and the duration.
+    pub fn protect(&mut self, rect: Rect, duration: u64) {
+        self.assert_active();
+        let account_id = env::predecessor_account_id();
+        assert!(!self.blacklist.contains(&account_id), "Account blacklisted");
+        assert!(self.shield_rate > 0, "Shields are disabled");
+        assert!(duration > 0, "duration must be positive");
+        rect.assert_valid();
+
         let mut account = self.get_mut_account(&account_id);
-        let new_pixels = pixels.len() as u32;
-        let cost = account.charge(Berry::Milk, new_pixels);
+        let pixel_seconds = Balance::from(rect.area()) * Balance::from(duration);
+        let cost = pixel_seconds.div_ceil(Balance::from(self.shield_rate));
+        account.charge(Berry::Milk, cost);
         self.used_milk += cost;
+        self.record_daily(|s| s.milk_used = (s.milk_used.0 + cost).into());
+
+        let expires_at = env::block_timestamp().saturating_add(duration.saturating_mul(FROM_NANO));
+        let num_pixels = self.board.protect(account.account_index, rect, expires_at);
+        log!("Protected {} pixels for {} Milk", num_pixels, cost);
+        self.save_account(account);
+    }
 
-        let mut old_owners = self.board.set_pixels(account.account_index, &pixels);
-        let replaced_pixels = old_owners.remove(&account.account_index).unwrap_or(0);
-        account.num_pixels += new_pixels - replaced_pixels;
+    /// Sets self-assessed prices (in Milk) of the predecessor's pixels.
+    /// Only available in the Harberger tax mode.
+    pub fn set_pixel_prices(&mut self, pixels: Vec<SetPixelPriceRequest>) {
+        self.assert_active();
+        assert!(
+            self.harberger_tax_rate > 0,
+            "Harberger tax mode is disabled"
+        );
+        let mut account = self.get_mut_account(&env::predecessor_account_id());
+        let (old_value, new_value) = self.board.set_prices(account.account_index, &pixels);
+        account.declared_value = account.declared_value + new_value - old_value;
         self.save_account(account);
+    }
 
-        for (account_index, num_pixels) in old_owners {
-            let mut account = self.get_internal_account_by_index(account_index).unwrap();
-            self.touch(&mut account);
-            account.num_pixels -= num_pixels;
-            self.save_account(account);
-        }
+    /// Charges the Harberger tax of the account and forecloses its pixels
+    /// if it can't pay. Anyone can call it.
+    pub fn collect_tax(&mut self, account_id: ValidAccountId) {
+        let mut account = self
+            .get_internal_account_by_id(account_id.as_ref())
+            .expect("account not found");
+        self.touch(&mut account);
+        self.save_account(account);
     }
 
-    pub fn withdraw_crop(&mut self) {
-        let recipient = env::predecessor_account_id();
-        assert!(!self.blacklist.contains(&recipient), "Account blacklisted");
+    /// Mints farmed Cheddar. `amount` defaults to the whole farmed balance.
+    /// Cheddar can be minted to another `receiver_id` (eg a cold wallet),
+    /// which must be already registered in the Cheddar token.
+    pub fn withdraw_crop(&mut self, amount: Option<U128>, receiver_id: Option<ValidAccountId>) {
+        let account_id = env::predecessor_account_id();
+        assert!(!self.blacklist.contains(&account_id), "Account blacklisted");
+        let recipient: AccountId = receiver_id
+            .map(|a| a.into())
+            .unwrap_or_else(|| account_id.clone());
 
         let mut account = self
-            .get_internal_account_by_id(&recipient)
+            .get_internal_account_by_id(&account_id)
             .expect("account not found");
         self.touch(&mut account);
+        self.record_activity(&mut account);
 
         let balance = account.balances[Berry::Cheddar as usize];
-        let mint_funded = account.mint_funded;
-
-        assert!(balance > 0, "zero balance");
-        account.balances[Berry::Cheddar as usize] = 0;
+        let amount = amount.map(|a| a.0).unwrap_or(balance);
+        assert!(amount > 0, "zero balance");
+        assert!(amount <= balance, "not enough balance");
+        account.balances[Berry::Cheddar as usize] = balance - amount;
+        account.pending_withdrawal += amount;
+        self.record_daily(|s| s.withdrawn_cheddar = (s.withdrawn_cheddar.0 + amount).into());
+        // storage in the Cheddar token is only funded for the account owner
+        let mint_funded = account.mint_funded || recipient != account_id;
         if !mint_funded {
             account.mint_funded = true;
             self.mint_funded += 1;
         }
         self.save_account(account);
-        let bal_str: U128 = balance.into();
+        let bal_str: U128 = amount.into();
 
         minter::ext_minter::ft_mint(
-            recipient.clone(),
-            bal_str.clone(),
+            recipient,
+            bal_str,
             Some("cheddar-draw reward".to_string()),
             &self.cheddar,
             if mint_funded { 1 } else { ONE_NEAR / 500 },
             GAS_FOR_FT_MINT,
         )
         .then(minter::ext_self::mint_callback(
-            recipient,
+            account_id,
             bal_str,
+            Some(!mint_funded),
             &env::current_account_id(),
             NO_DEPOSIT,
             GAS_FOR_RESOLVE_MINT,
         ));
     }
 
+    /// Returns the deposited (not farmed) Cheddar to the predecessor with
+    /// `ft_transfer`. Farmed Cheddar is withdrawn with `withdraw_crop`.
+    pub fn withdraw_deposit(&mut self) {
+        let recipient = env::predecessor_account_id();
+        assert!(!self.blacklist.contains(&recipient), "Account blacklisted");
+
+        let mut account = self
+            .get_internal_account_by_id(&recipient)
+            .expect("account not found");
+        let balance = account.deposited_cheddar;
+        assert!(balance > 0, "zero balance");
+        account.deposited_cheddar = 0;
+        account.pending_withdrawal += balance;
+        self.save_account(account);
+        let bal_str: U128 = balance.into();
+
+        minter::ext_ft::ft_transfer(
+            recipient.clone(),
+            bal_str,
+            Some("cheddar-draw deposit".to_string()),
+            &self.cheddar,
+            1,
+            GAS_FOR_FT_TRANSFER,
+        )
+        .then(minter::ext_self::transfer_callback(
+            recipient,
+            bal_str,
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_RESOLVE_TRANSFER,
+        ));
+    }
+
+    pub fn get_payment_tokens(&self) -> Vec<(AccountId, PaymentToken)> {
+        self.payment_tokens.to_vec()
+    }
+
+    pub fn get_milk_cheddar_price_history(&self, from_index: u64, limit: u64) -> Vec<PriceChange> {
+        let to_index = std::cmp::min(
+            from_index.saturating_add(limit),
+            self.milk_cheddar_price_history.len(),
+        );
+        (from_index..to_index)
+            .map(|i| self.milk_cheddar_price_history.get(i).unwrap())
+            .collect()
+    }
+
     pub fn get_num_accounts(&self) -> u32 {
         self.num_accounts
     }
@@ -252,16 +488,18 @@ impl Place {
 
     /// Withdraws earned NEAR (from Milk sells) to the treasury. Anyone can do it.
     /// Returns amount of transferred NEAR.
-    pub fn withdraw_near(&self) -> U128 {
+    pub fn withdraw_near(&mut self) -> U128 {
         let account_balance = env::account_balance();
         let storage_usage = env::storage_usage();
-        let locked_for_storage = Balance::from(storage_usage) * STORAGE_PRICE_PER_BYTE + SAFETY_BAR;
+        let locked_for_storage =
+            Balance::from(storage_usage) * STORAGE_PRICE_PER_BYTE + SAFETY_BAR + self.storage_pool;
         if account_balance <= locked_for_storage {
             return 0.into();
         }
         let liquid_balance = account_balance - locked_for_storage;
         log!("withdrawing treasury {} {}", liquid_balance, &self.treasury);
         Promise::new(self.treasury.clone()).transfer(liquid_balance);
+        self.treasury_withdrawn += liquid_balance;
         return liquid_balance.into();
     }
 
@@ -287,6 +525,113 @@ impl Place {
         self.milk_price = price.into();
     }
 
+    /// Sets Milk price in Cheddar. Can be called by the admin or the price
+    /// oracle. The price must be within `milk_cheddar_price_bounds`.
+    pub fn set_milk_price_in_cheddar(&mut self, price: U128) {
+        let caller = env::predecessor_account_id();
+        assert!(
+            caller == self.admin || self.price_oracle.as_ref() == Some(&caller),
+            "Not an admin or price oracle"
+        );
+        let (min, max) = self.milk_cheddar_price_bounds;
+        assert!(price.0 >= min && price.0 <= max, "price is out of bounds");
+        self.internal_set_milk_cheddar_price(price.0, caller);
+    }
+
+    pub fn set_milk_cheddar_price_bounds(&mut self, min: U128, max: U128) {
+        self.only_admin();
+        assert!(min.0 > 0 && min.0 <= max.0, "invalid bounds");
+        self.milk_cheddar_price_bounds = (min.into(), max.into());
+        let price = self.milk_cheddar_price.clamp(min.0, max.0);
+        if price != self.milk_cheddar_price {
+            self.internal_set_milk_cheddar_price(price, env::predecessor_account_id());
+        }
+    }
+
+    pub fn set_price_oracle(&mut self, oracle: Option<ValidAccountId>) {
+        self.only_admin();
+        self.price_oracle = oracle.map(|a| a.into());
+    }
+
+    /// Adds a NEP-141 token accepted as a payment for Milk or updates its price.
+    /// `milk_price` is the price of one Milk in the token units.
+    pub fn set_payment_token(&mut self, token: ValidAccountId, milk_price: U128, decimals: u8) {
+        self.only_admin();
+        assert!(
+            token.as_ref() != &self.cheddar,
+            "Cheddar can't be a payment token"
+        );
+        assert!(milk_price.0 > 0, "milk_price must be positive");
+        assert!(
+            decimals <= MAX_TOKEN_DECIMALS,
+            "decimals must be at most {}",
+            MAX_TOKEN_DECIMALS
+        );
+        let token: AccountId = token.into();
+        let mut t = self.payment_tokens.get(&token).unwrap_or(PaymentToken {
+            milk_price,
+            decimals,
+            received: 0.into(),
+            bought_milk: 0.into(),
+        });
+        t.milk_price = milk_price;
+        t.decimals = decimals;
+        self.payment_tokens.insert(&token, &t);
+    }
+
+    pub fn remove_payment_token(&mut self, token: ValidAccountId) {
+        self.only_admin();
+        self.payment_tokens.remove(token.as_ref());
+    }
+
+    /// Sets (or removes) the bonding curve used for Milk sold for NEAR.
+    pub fn set_milk_curve(&mut self, curve: Option<MilkCurve>) {
+        self.only_admin();
+        if let Some(c) = &curve {
+            c.assert_valid();
+        }
+        self.milk_curve = curve;
+    }
+
+    /// Sets bonus tiers for Milk bought with NEAR and with Cheddar.
+    /// Tiers must be sorted by threshold.
+    pub fn set_discount_tiers(
+        &mut self,
+        near_tiers: Vec<DiscountTier>,
+        cheddar_tiers: Vec<DiscountTier>,
+    ) {
+        self.only_admin();
+        assert_valid_tiers(&near_tiers);
+        assert_valid_tiers(&cheddar_tiers);
+        self.discount_tiers = near_tiers;
+        self.cheddar_discount_tiers = cheddar_tiers;
+    }
+
+    /// Sets the dynamic pixel price parameters. `half_life` is in seconds,
+    /// zero makes every pixel cost 1 Milk.
+    pub fn set_pixel_pricing(&mut self, half_life: u64, max_doublings: u8) {
+        self.only_admin();
+        assert!(max_doublings <= 16, "max_doublings must be at most 16");
+        self.pixel_pricing = PixelPricing {
+            half_life: half_life * FROM_NANO,
+            max_doublings,
+        };
+    }
+
+    /// Sets the Harberger tax rate in basis points of the declared pixel value
+    /// per day. Zero disables the Harberger tax mode.
+    pub fn set_harberger_tax_rate(&mut self, tax_rate: u32) {
+        self.only_admin();
+        self.harberger_tax_rate = tax_rate;
+    }
+
+    /// Sets how many pixel-seconds of shield protection one Milk buys.
+    /// Zero disables shields.
+    pub fn set_shield_rate(&mut self, shield_rate: u64) {
+        self.only_admin();
+        self.shield_rate = shield_rate;
+    }
+
     /// set end date in unix timestamp (seconds)
     pub fn set_start_end(&mut self, starts: u64, ends: u64) {
         self.only_admin();
@@ -309,50 +654,20 @@ impl Place {
         self.blacklist.contains(&account)
     }
 
-    /// Dangerous: it removes the board (not only cleans the content, but
-    /// removes all board rows and cols)
+    /// Dangerous: it removes the board content and stops the game. All
+    /// pixels go back to the contract account.
     pub fn delete_board(&mut self) {
         self.only_admin();
         self.ends = env::block_timestamp();
         self.is_active = false;
-
-        self.board.lines.clear();
-        let default_line = PixelLine::default();
-        for _ in 0..BOARD_HEIGHT {
-            self.board.lines.push(&default_line);
-        }
-        let mut a = self.get_internal_account_by_index(0).unwrap();
-        self.touch(&mut a);
-        a.num_pixels = TOTAL_NUM_PIXELS;
-        self.save_account(a);
+        self.clear_board();
     }
 
     /// Resets the board state.
     /// NOTE: should be called with caution
     pub fn reset_board(&mut self) {
         self.only_admin();
-
-        let default_line = PixelLine::default();
-        let mut users = HashSet::new();
-        for i in 0..BOARD_HEIGHT {
-            for pl in self.board.lines.get(i.into()) {
-                for p in pl.0 {
-                    users.insert(p.owner_id);
-                }
-            }
-            self.board.lines.replace(i.into(), &default_line);
-        }
-        for u_idx in users {
-            let mut a = self.get_internal_account_by_index(u_idx).unwrap();
-            self.touch(&mut a);
-            a.num_pixels = 0;
-            self.save_account(a);
-        }
-
-        let mut a = self.get_internal_account_by_index(0).unwrap();
-        self.touch(&mut a);
-        a.num_pixels = TOTAL_NUM_PIXELS;
-        self.save_account(a);
+        self.clear_board();
     }
 }
 
@@ -363,7 +678,10 @@ mod tests {
 
     use super::*;
 
-    use near_sdk::{testing_env, MockedBlockchain, VMContext};
+    use crate::migrate::{OldPixelBoard, OldPlace, STATE_VERSION};
+    use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+    use near_contract_standards::storage_management::StorageManagement;
+    use near_sdk::{testing_env, MockedBlockchain, PromiseOrValue, PromiseResult, VMContext};
 
     pub fn get_context(block_timestamp: u64, is_view: bool) -> VMContext {
         VMContext {
@@ -386,6 +704,55 @@ mod tests {
         }
     }
 
+    const STARTS: u64 = 1_700_000_000;
+    const ENDS: u64 = 1_800_000_000;
+
+    /// Creates a contract with a running game. Returns the context set at the
+    /// game start time.
+    fn setup() -> (VMContext, Place) {
+        let (mut context, mut contract) = setup_without_storage_pool();
+        context.attached_deposit = ONE_NEAR;
+        testing_env!(context.clone());
+        contract.fund_storage_pool();
+        context.attached_deposit = 0;
+        testing_env!(context.clone());
+        (context, contract)
+    }
+
+    fn setup_without_storage_pool() -> (VMContext, Place) {
+        let mut context = get_context(STARTS * FROM_NANO, false);
+        testing_env!(context.clone());
+        let mut contract = Place::new(
+            "token.cheddar.near".try_into().unwrap(),
+            "admin.cheddar.near".try_into().unwrap(),
+            "treasury.cheddar.near".try_into().unwrap(),
+            ENDS,
+        );
+        context.predecessor_account_id = "admin.cheddar.near".to_string();
+        testing_env!(context.clone());
+        contract.set_start_end(STARTS, ENDS);
+        (context, contract)
+    }
+
+    fn pixel(x: u8, y: u8, color: u32) -> SetPixelRequest {
+        SetPixelRequest {
+            x,
+            y,
+            color,
+            price: None,
+        }
+    }
+
+    fn quote(contract: &Place, account_id: &str, pixels: Vec<SetPixelRequest>) -> Balance {
+        contract
+            .quote_draw(account_id.try_into().unwrap(), pixels)
+            .into()
+    }
+
+    fn milk_balance(contract: &Place, account_id: &str) -> u32 {
+        contract.get_account_balance(account_id.try_into().unwrap())
+    }
+
     #[test]
     fn test_new() {
         let mut context = get_context(3_600_000_000_000, false);
@@ -393,6 +760,8 @@ mod tests {
         let contract = Place::new(
             "token.cheddar.near".try_into().unwrap(),
             "admin.cheddar.near".try_into().unwrap(),
+            "treasury.cheddar.near".try_into().unwrap(),
+            ENDS,
         );
 
         context.is_view = true;
@@ -402,4 +771,1804 @@ mod tests {
             vec![0u32; BOARD_HEIGHT as usize]
         );
     }
+
+    #[test]
+    fn test_dynamic_pixel_price() {
+        let (mut context, mut contract) = setup();
+        context.predecessor_account_id = "alice.near".to_string();
+        testing_env!(context.clone());
+        contract.draw(vec![pixel(1, 1, 0xff0000), pixel(2, 1, 0xff0000)]);
+        assert_eq!(milk_balance(&contract, "alice.near"), 0);
+
+        // repainting own pixels is not more expensive
+        assert_eq!(
+            quote(&contract, "alice.near", vec![pixel(1, 1, 0xff0000)]),
+            1
+        );
+
+        // overwriting a fresh pixel of another account doubles the price
+        context.predecessor_account_id = "bob.near".to_string();
+        testing_env!(context.clone());
+        assert_eq!(quote(&contract, "bob.near", vec![pixel(1, 1, 0x00ff00)]), 2);
+        contract.draw(vec![pixel(1, 1, 0x00ff00)]);
+        assert_eq!(milk_balance(&contract, "bob.near"), 0);
+
+        // contested pixel gets more expensive, but the surcharge decays
+        let carol_pixels = || vec![pixel(1, 1, 0x0000ff)];
+        assert_eq!(quote(&contract, "carol.near", carol_pixels()), 4);
+        context.block_timestamp += 60 * 60 * FROM_NANO;
+        testing_env!(context.clone());
+        assert_eq!(quote(&contract, "carol.near", carol_pixels()), 2);
+        context.block_timestamp += 2 * 60 * 60 * FROM_NANO;
+        testing_env!(context.clone());
+        assert_eq!(quote(&contract, "carol.near", carol_pixels()), 1);
+    }
+
+    #[test]
+    fn test_harberger_takeover_and_foreclosure() {
+        let (mut context, mut contract) = setup();
+        contract.set_pixel_pricing(0, 0);
+        contract.set_harberger_tax_rate(10_000);
+
+        context.predecessor_account_id = "alice.near".to_string();
+        testing_env!(context.clone());
+        let mut request = pixel(1, 1, 0xff0000);
+        request.price = Some(1);
+        contract.draw(vec![request]);
+        assert_eq!(milk_balance(&contract, "alice.near"), 1);
+
+        // bob pays the base cost and the declared price to alice
+        context.predecessor_account_id = "bob.near".to_string();
+        testing_env!(context.clone());
+        assert_eq!(quote(&contract, "bob.near", vec![pixel(1, 1, 0)]), 2);
+        let mut request = pixel(1, 1, 0x00ff00);
+        request.price = Some(4);
+        contract.draw(vec![request]);
+        assert_eq!(milk_balance(&contract, "bob.near"), 0);
+        assert_eq!(milk_balance(&contract, "alice.near"), 2);
+        assert_eq!(contract.get_pixel_prices(vec![1])[0][1], 4);
+
+        // bob can't pay the daily tax (100% of 4 Milk)
+        context.block_timestamp += 24 * 60 * 60 * FROM_NANO;
+        testing_env!(context.clone());
+        contract.collect_tax("bob.near".try_into().unwrap());
+        assert_eq!(
+            contract.get_account_num_pixels("bob.near".try_into().unwrap()),
+            0
+        );
+        assert_eq!(contract.board.get_line(1).0[1].owner_id, 0);
+        assert_eq!(contract.get_pixel_prices(vec![1])[0][1], 0);
+    }
+
+    #[test]
+    fn test_shields() {
+        let (mut context, mut contract) = setup();
+        contract.set_pixel_pricing(0, 0);
+
+        context.predecessor_account_id = "alice.near".to_string();
+        testing_env!(context.clone());
+        contract.draw(vec![pixel(1, 1, 0xff0000)]);
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width: 10,
+            height: 10,
+        };
+        // 100 pixels for one hour cost 1 Milk
+        contract.protect(rect, 60 * 60);
+        assert_eq!(milk_balance(&contract, "alice.near"), 0);
+        let far = Rect {
+            x: 50,
+            y: 50,
+            width: 1,
+            height: 1,
+        };
+        assert_eq!(contract.get_shields(rect).len(), 1);
+        assert_eq!(contract.get_shields(far).len(), 0);
+
+        // not owned pixels inside the shield are not protected
+        context.predecessor_account_id = "bob.near".to_string();
+        testing_env!(context.clone());
+        contract.draw(vec![pixel(2, 1, 0x00ff00)]);
+
+        context.block_timestamp += 60 * 60 * FROM_NANO;
+        testing_env!(context.clone());
+        assert_eq!(contract.get_shields(rect).len(), 0);
+        contract.draw(vec![pixel(1, 1, 0x00ff00)]);
+        assert_eq!(contract.board.get_line(1).0[1].color, 0x00ff00);
+    }
+
+    #[test]
+    #[should_panic(expected = "Too many active shields")]
+    fn test_shields_limit_per_account() {
+        let (mut context, mut contract) = setup();
+        contract.set_pixel_pricing(0, 0);
+        contract.set_shield_rate(u64::MAX);
+        let rect = Rect {
+            x: 1,
+            y: 1,
+            width: 1,
+            height: 1,
+        };
+
+        context.predecessor_account_id = "alice.near".to_string();
+        context.attached_deposit = ONE_NEAR;
+        testing_env!(context.clone());
+        contract.buy_tokens();
+        context.attached_deposit = 0;
+        testing_env!(context.clone());
+        contract.draw(vec![pixel(1, 1, 0xff0000)]);
+        // the expiration time saturates
+        for _ in 0..MAX_ACTIVE_SHIELDS {
+            contract.protect(rect, u64::MAX);
+        }
+        assert_eq!(contract.get_shields(rect).len(), MAX_ACTIVE_SHIELDS);
+
+        // other accounts can still buy shields
+        context.predecessor_account_id = "bob.near".to_string();
+        testing_env!(context.clone());
+        contract.draw(vec![pixel(2, 1, 0xff0000)]);
+        contract.protect(rect, 60);
+
+        context.predecessor_account_id = "alice.near".to_string();
+        testing_env!(context.clone());
+        contract.protect(rect, 60);
+    }
+
+    #[test]
+    #[should_panic(expected = "Pixel is protected by a shield")]
+    fn test_shield_rejects_overwrite() {
+        let (mut context, mut contract) = setup();
+        contract.set_pixel_pricing(0, 0);
+
+        context.predecessor_account_id = "alice.near".to_string();
+        testing_env!(context.clone());
+        contract.draw(vec![pixel(1, 1, 0xff0000)]);
+        let rect = Rect {
+            x: 1,
+            y: 1,
+            width: 1,
+            height: 1,
+        };
+        contract.protect(rect, 60 * 60);
+
+        context.predecessor_account_id = "bob.near".to_string();
+        testing_env!(context.clone());
+        contract.draw(vec![pixel(1, 1, 0x00ff00)]);
+    }
+
+    #[test]
+    fn test_milk_curve() {
+        let linear = MilkCurve::Linear {
+            base: 10.into(),
+            slope: 2.into(),
+        };
+        // prices: 10, 12, 14, 16, ...
+        assert_eq!(linear.buy(0, 35), (2, 22));
+        assert_eq!(linear.buy(0, 36), (3, 36));
+        assert_eq!(linear.buy(3, 16), (1, 16));
+
+        let exponential = MilkCurve::Exponential {
+            base: 100.into(),
+            growth: 5_000,
+            step: 2.into(),
+        };
+        // prices: 100, 100, 150, 150, 225, ...
+        assert_eq!(exponential.buy(0, 499), (3, 350));
+        assert_eq!(exponential.buy(3, 375), (2, 375));
+        // the price after a huge supply doesn't fit into u128
+        assert_eq!(exponential.buy(Balance::MAX / 2, ONE_NEAR), (0, 0));
+
+        let (mut context, mut contract) = setup();
+        contract.set_milk_curve(Some(MilkCurve::Linear {
+            base: (ONE_NEAR / 10).into(),
+            slope: (ONE_NEAR / 100).into(),
+        }));
+        // 0.1 + 0.11 + 0.12 + 0.13 = 0.46 NEAR
+        assert_eq!(contract.quote_milk((ONE_NEAR / 2).into()).0, 4);
+
+        context.predecessor_account_id = "alice.near".to_string();
+        context.attached_deposit = ONE_NEAR / 2;
+        testing_env!(context.clone());
+        contract.buy_tokens();
+        assert_eq!(milk_balance(&contract, "alice.near"), 6);
+        // 0.14 + 0.15 + 0.16 = 0.45 NEAR
+        assert_eq!(contract.quote_milk((ONE_NEAR / 2).into()).0, 3);
+    }
+
+    #[test]
+    fn test_milk_curve_small_step() {
+        let (mut context, mut contract) = setup();
+        // one Milk per step of a flat curve, the purchase doesn't iterate over the steps
+        contract.set_milk_curve(Some(MilkCurve::Exponential {
+            base: 1.into(),
+            growth: 0,
+            step: 1.into(),
+        }));
+        assert_eq!(contract.quote_milk(ONE_NEAR.into()).0, ONE_NEAR);
+
+        contract.set_milk_curve(Some(MilkCurve::Exponential {
+            base: (ONE_NEAR / 10).into(),
+            growth: 1_000,
+            step: 1.into(),
+        }));
+        // 0.1 + 0.11 + 0.121 + 0.1331 = 0.4641 NEAR, the rest is refunded
+        context.predecessor_account_id = "alice.near".to_string();
+        context.attached_deposit = ONE_NEAR / 2;
+        testing_env!(context.clone());
+        contract.buy_tokens();
+        assert_eq!(milk_balance(&contract, "alice.near"), 6);
+        assert_eq!(contract.stats().near_collected.0, 4641 * ONE_NEAR / 10_000);
+    }
+
+    #[test]
+    fn test_discount_tiers() {
+        let (_, mut contract) = setup();
+        // 1 NEAR = 400 Milk, 5 NEAR gets 20% bonus
+        assert_eq!(contract.quote_milk(ONE_NEAR.into()).0, 400);
+        assert_eq!(contract.quote_milk((5 * ONE_NEAR).into()).0, 2400);
+
+        contract.set_discount_tiers(
+            vec![
+                DiscountTier {
+                    threshold: ONE_NEAR.into(),
+                    bonus: 500,
+                },
+                DiscountTier {
+                    threshold: (10 * ONE_NEAR).into(),
+                    bonus: 5_000,
+                },
+            ],
+            vec![],
+        );
+        assert_eq!(contract.quote_milk((ONE_NEAR / 2).into()).0, 200);
+        assert_eq!(contract.quote_milk((5 * ONE_NEAR).into()).0, 2100);
+        assert_eq!(contract.quote_milk((10 * ONE_NEAR).into()).0, 6000);
+    }
+
+    #[test]
+    fn test_milk_cheddar_price() {
+        let (mut context, mut contract) = setup();
+        let price = ONE_NEAR / 400 * 160;
+        assert_eq!(contract.get_settings().milk_cheddar_price.0, price);
+
+        contract.set_price_oracle(Some("oracle.near".try_into().unwrap()));
+        context.predecessor_account_id = "oracle.near".to_string();
+        testing_env!(context.clone());
+        contract.set_milk_price_in_cheddar((price * 2).into());
+        assert_eq!(contract.stats().cheddar_milk_price.0, price * 2);
+
+        let history = contract.get_milk_cheddar_price_history(0, u64::MAX);
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].price.0, price);
+        assert_eq!(history[0].updated_by, "admin.cheddar.near");
+        assert_eq!(history[1].price.0, price * 2);
+        assert_eq!(history[1].updated_by, "oracle.near");
+
+        // the current price is moved into new bounds
+        context.predecessor_account_id = "admin.cheddar.near".to_string();
+        testing_env!(context.clone());
+        contract.set_milk_cheddar_price_bounds(price.into(), (price * 3 / 2).into());
+        assert_eq!(contract.get_settings().milk_cheddar_price.0, price * 3 / 2);
+        assert_eq!(
+            contract.get_milk_cheddar_price_history(2, 10)[0].price.0,
+            price * 3 / 2
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "price is out of bounds")]
+    fn test_milk_cheddar_price_bounds() {
+        let (_, mut contract) = setup();
+        contract.set_milk_price_in_cheddar((ONE_NEAR * 10).into());
+    }
+
+    #[test]
+    fn test_ft_on_transfer_actions() {
+        let (mut context, mut contract) = setup();
+        contract.set_pixel_pricing(0, 0);
+        let price = contract.get_settings().milk_cheddar_price.0;
+
+        context.predecessor_account_id = "alice.near".to_string();
+        testing_env!(context.clone());
+        contract.register_account();
+
+        context.predecessor_account_id = "token.cheddar.near".to_string();
+        testing_env!(context.clone());
+        // plain deposit
+        let unused = contract.ft_on_transfer(
+            "alice.near".try_into().unwrap(),
+            price.into(),
+            "to farm".to_string(),
+        );
+        assert!(matches!(unused, PromiseOrValue::Value(U128(0))));
+        let alice = contract
+            .get_account("alice.near".try_into().unwrap())
+            .unwrap();
+        assert_eq!(alice.deposited_cheddar.0, price);
+
+        // buy milk, the remainder is returned
+        let unused = contract.ft_on_transfer(
+            "alice.near".try_into().unwrap(),
+            (3 * price + 5).into(),
+            r#"{"action": "buy_milk"}"#.to_string(),
+        );
+        assert!(matches!(unused, PromiseOrValue::Value(U128(5))));
+        assert_eq!(milk_balance(&contract, "alice.near"), 5);
+
+        // draw: 5 Milk owned, 2 are missing
+        let msg = r#"{"action": "draw", "pixels": [
+            {"x": 0, "y": 0, "color": 1}, {"x": 1, "y": 0, "color": 1},
+            {"x": 2, "y": 0, "color": 1}, {"x": 3, "y": 0, "color": 1},
+            {"x": 4, "y": 0, "color": 1}, {"x": 5, "y": 0, "color": 1},
+            {"x": 6, "y": 0, "color": 1}]}"#;
+        let unused = contract.ft_on_transfer(
+            "alice.near".try_into().unwrap(),
+            (10 * price).into(),
+            msg.to_string(),
+        );
+        assert!(matches!(unused, PromiseOrValue::Value(U128(u)) if u == 8 * price));
+        assert_eq!(milk_balance(&contract, "alice.near"), 0);
+        assert_eq!(
+            contract.get_account_num_pixels("alice.near".try_into().unwrap()),
+            7
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "decimals must be at most 24")]
+    fn test_payment_token_decimals_too_large() {
+        let (_, mut contract) = setup();
+        contract.set_payment_token("usdc.near".try_into().unwrap(), 100_000.into(), 39);
+    }
+
+    #[test]
+    fn test_payment_tokens() {
+        let (mut context, mut contract) = setup();
+        // 1 USDC (6 decimals) = 10 Milk
+        contract.set_payment_token("usdc.near".try_into().unwrap(), 100_000.into(), 6);
+        context.predecessor_account_id = "alice.near".to_string();
+        testing_env!(context.clone());
+        contract.register_account();
+
+        context.predecessor_account_id = "usdc.near".to_string();
+        testing_env!(context.clone());
+        let unused = contract.ft_on_transfer(
+            "alice.near".try_into().unwrap(),
+            1_000_001.into(),
+            "".to_string(),
+        );
+        assert!(matches!(unused, PromiseOrValue::Value(U128(1))));
+        assert_eq!(milk_balance(&contract, "alice.near"), 12);
+
+        let stats = contract.stats();
+        assert_eq!(stats.bought_milk.0, 10);
+        assert_eq!(stats.payment_tokens[0].0, "usdc.near");
+        assert_eq!(stats.payment_tokens[0].1.received.0, 1_000_000);
+
+        // 2000 Milk are worth 5 NEAR: 20% bonus of the NEAR discount tier
+        contract.ft_on_transfer(
+            "alice.near".try_into().unwrap(),
+            200_000_000.into(),
+            "".to_string(),
+        );
+        assert_eq!(milk_balance(&contract, "alice.near"), 12 + 2400);
+    }
+
+    #[test]
+    #[should_panic(expected = "Token is not accepted as payment")]
+    fn test_not_whitelisted_token() {
+        let (mut context, mut contract) = setup();
+        context.predecessor_account_id = "usdc.near".to_string();
+        testing_env!(context.clone());
+        contract.ft_on_transfer(
+            "alice.near".try_into().unwrap(),
+            1_000_000.into(),
+            "".to_string(),
+        );
+    }
+
+    #[test]
+    fn test_deposit_from_unregistered_account() {
+        let (mut context, mut contract) = setup_without_storage_pool();
+
+        // empty storage pool: the deposit is returned
+        context.predecessor_account_id = "token.cheddar.near".to_string();
+        testing_env!(context.clone());
+        let unused = contract.ft_on_transfer(
+            "alice.near".try_into().unwrap(),
+            ONE_NEAR.into(),
+            "".to_string(),
+        );
+        assert!(matches!(unused, PromiseOrValue::Value(U128(u)) if u == ONE_NEAR));
+        assert!(!contract.account_exists("alice.near".try_into().unwrap()));
+
+        context.attached_deposit = ONE_NEAR;
+        testing_env!(context.clone());
+        contract.fund_storage_pool();
+
+        // the account is registered with the pool funds
+        context.attached_deposit = 0;
+        testing_env!(context.clone());
+        let unused = contract.ft_on_transfer(
+            "alice.near".try_into().unwrap(),
+            ONE_NEAR.into(),
+            "".to_string(),
+        );
+        assert!(matches!(unused, PromiseOrValue::Value(U128(0))));
+        let alice = contract
+            .get_account("alice.near".try_into().unwrap())
+            .unwrap();
+        assert_eq!(alice.deposited_cheddar.0, ONE_NEAR);
+        assert_eq!(
+            contract.get_storage_pool().0,
+            ONE_NEAR - contract.storage_balance_bounds().min.0
+        );
+    }
+
+    #[test]
+    fn test_register_account_without_storage_pool() {
+        let (mut context, mut contract) = setup_without_storage_pool();
+        context.predecessor_account_id = "alice.near".to_string();
+        testing_env!(context.clone());
+        contract.register_account();
+        assert!(contract.account_exists("alice.near".try_into().unwrap()));
+        assert_eq!(contract.get_storage_pool().0, 0);
+
+        // the storage of a new account is paid from the Milk payment
+        context.predecessor_account_id = "bob.near".to_string();
+        context.attached_deposit = ONE_NEAR;
+        testing_env!(context.clone());
+        contract.buy_tokens();
+        assert_eq!(contract.get_storage_pool().0, 0);
+        let near_paid = ONE_NEAR - contract.storage_balance_bounds().min.0;
+        assert_eq!(
+            u128::from(milk_balance(&contract, "bob.near")),
+            u128::from(DEFAULT_MILK_BALANCE) + near_paid / contract.milk_price
+        );
+        assert_eq!(
+            contract.stats().near_collected.0,
+            near_paid / contract.milk_price * contract.milk_price
+        );
+    }
+
+    #[test]
+    fn test_storage_deposit() {
+        let (mut context, mut contract) = setup_without_storage_pool();
+        let cost = contract.storage_balance_bounds().min.0;
+        context.predecessor_account_id = "alice.near".to_string();
+        context.attached_deposit = cost;
+        testing_env!(context.clone());
+        contract.storage_deposit(None, None);
+        assert!(contract.account_exists("alice.near".try_into().unwrap()));
+        assert_eq!(contract.get_storage_pool().0, 0);
+    }
+
+    #[test]
+    fn test_failed_mint_refund() {
+        let (mut context, mut contract) = setup();
+        context.predecessor_account_id = "alice.near".to_string();
+        testing_env!(context.clone());
+        contract.draw(vec![pixel(1, 1, 0xff0000)]);
+
+        context.block_timestamp += 60 * FROM_NANO;
+        testing_env!(context.clone());
+        let alice_id: ValidAccountId = "alice.near".try_into().unwrap();
+        let farmed = contract
+            .get_account(alice_id.clone())
+            .unwrap()
+            .banana_balance
+            .0;
+        contract.withdraw_crop(Some((farmed / 2).into()), None);
+        assert_eq!(
+            contract.get_pending_withdrawal(alice_id.clone()).0,
+            farmed / 2
+        );
+        assert_eq!(contract.mint_funded, 1);
+
+        // more Cheddar is farmed before the mint fails
+        context.block_timestamp += 60 * FROM_NANO;
+        context.predecessor_account_id = context.current_account_id.clone();
+        testing_env!(
+            context.clone(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![PromiseResult::Failed]
+        );
+        contract.mint_callback("alice.near".to_string(), (farmed / 2).into(), Some(true));
+        let alice = contract.get_account(alice_id.clone()).unwrap();
+        assert_eq!(alice.banana_balance.0, 2 * farmed);
+        assert_eq!(alice.pending_withdrawal.0, 0);
+        let stats = contract.stats();
+        assert_eq!(stats.mint_funded, 0);
+        assert_eq!(stats.refunded_cheddar.0, farmed / 2);
+        assert_eq!(stats.withdrawn_cheddar.0, 0);
+        // the callback doesn't touch the account
+        assert_eq!(stats.farmed_cheddar.0, farmed);
+    }
+
+    #[test]
+    fn test_mint_callback_scheduled_before_upgrade() {
+        let (mut context, mut contract) = setup();
+        context.predecessor_account_id = "alice.near".to_string();
+        testing_env!(context.clone());
+        contract.register_account();
+
+        // the old callback doesn't pass `funded`, the withdrawal isn't pending
+        context.predecessor_account_id = context.current_account_id.clone();
+        testing_env!(
+            context.clone(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![PromiseResult::Failed]
+        );
+        contract.mint_callback("alice.near".to_string(), 10.into(), None);
+        let alice = contract
+            .get_account("alice.near".try_into().unwrap())
+            .unwrap();
+        assert_eq!(alice.banana_balance.0, 10);
+        assert_eq!(alice.pending_withdrawal.0, 0);
+        assert_eq!(contract.stats().refunded_cheddar.0, 10);
+    }
+
+    #[test]
+    fn test_migrate() {
+        let mut context = get_context(STARTS * FROM_NANO, false);
+        testing_env!(context.clone());
+        let mut lines = Vector::new(b"p".to_vec());
+        let mut line = PixelLine::default();
+        line.0[1] = Pixel {
+            color: 0xff0000,
+            owner_id: 1,
+        };
+        let empty_line = PixelLine::default();
+        for i in 0..BOARD_HEIGHT {
+            lines.push(if i == 1 { &line } else { &empty_line });
+        }
+        let old_account = |account_id: &str, account_index, balances, num_pixels| {
+            UpgradableAccount::BananaAccount(AccountV1 {
+                account_id: account_id.to_string(),
+                account_index,
+                balances,
+                num_pixels,
+                claim_timestamp: STARTS * FROM_NANO,
+                mint_funded: false,
+            })
+        };
+        let mut account_indices = LookupMap::new(b"i".to_vec());
+        let mut accounts = LookupMap::new(b"u".to_vec());
+        account_indices.insert(&context.current_account_id, &0);
+        accounts.insert(
+            &0,
+            &old_account(
+                &context.current_account_id,
+                0,
+                vec![2, 0],
+                TOTAL_NUM_PIXELS - 1,
+            ),
+        );
+        account_indices.insert(&"alice.near".to_string(), &1);
+        accounts.insert(&1, &old_account("alice.near", 1, vec![10, 0], 1));
+        env::state_write(&OldPlace {
+            account_indices,
+            accounts,
+            num_accounts: 2,
+            board: OldPixelBoard {
+                lines,
+                line_versions: vec![0; BOARD_HEIGHT as usize],
+            },
+            last_reward_timestamp: 0,
+            bought_balances: vec![10, 0],
+            used_milk: 1,
+            farmed_cheddar: 0,
+            is_active: true,
+            admin: "admin.cheddar.near".to_string(),
+            cheddar: "token.cheddar.near".to_string(),
+            treasury: "treasury.cheddar.near".to_string(),
+            mint_funded: 0,
+            reward_rate: 1,
+            milk_price: ONE_NEAR / 400,
+            blacklist: LookupSet::new(b"b".to_vec()),
+            starts: STARTS * FROM_NANO,
+            ends: ENDS * FROM_NANO,
+        });
+
+        context.predecessor_account_id = context.current_account_id.clone();
+        testing_env!(context.clone());
+        let mut contract = Place::migrate();
+        assert_eq!(contract.state_version, STATE_VERSION);
+        let stats = contract.board_stats();
+        assert_eq!(stats.num_owners, 1);
+        assert_eq!(stats.contract_pixels, TOTAL_NUM_PIXELS - 1);
+        assert_eq!(stats.color_buckets[color_bucket(0xff0000)], 1);
+        let top = contract.get_leaderboard(LeaderboardKind::Pixels);
+        assert_eq!(top.len(), 1);
+        assert_eq!(top[0].account_id, "alice.near");
+        assert_eq!(contract.stats().bought_milk.0, 10);
+        assert_eq!(contract.get_milk_cheddar_price_history(0, 10).len(), 1);
+
+        context.predecessor_account_id = "admin.cheddar.near".to_string();
+        testing_env!(context.clone());
+        contract.set_pixel_pricing(0, 0);
+        context.predecessor_account_id = "alice.near".to_string();
+        testing_env!(context.clone());
+        contract.draw(vec![pixel(2, 1, 0xff0000)]);
+        assert_eq!(milk_balance(&contract, "alice.near"), 9);
+        assert_eq!(
+            contract.get_account_num_pixels("alice.near".try_into().unwrap()),
+            2
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "State is not the state of the first deployed version")]
+    fn test_migrate_current_state() {
+        let (mut context, contract) = setup();
+        env::state_write(&contract);
+        context.predecessor_account_id = context.current_account_id.clone();
+        testing_env!(context.clone());
+        Place::migrate();
+    }
+
+    #[test]
+    fn test_load_old_account() {
+        let (mut context, mut contract) = setup();
+        contract.set_pixel_pricing(0, 0);
+        let old = UpgradableAccount::BananaAccount(AccountV1 {
+            account_id: "alice.near".to_string(),
+            account_index: 1,
+            balances: vec![10, 0],
+            num_pixels: 0,
+            claim_timestamp: STARTS * FROM_NANO,
+            mint_funded: false,
+        });
+        contract.accounts.insert(&1, &old);
+        contract
+            .account_indices
+            .insert(&"alice.near".to_string(), &1);
+        contract.num_accounts = 2;
+
+        context.predecessor_account_id = "alice.near".to_string();
+        testing_env!(context.clone());
+        contract.draw(vec![pixel(1, 1, 0xff0000)]);
+        assert_eq!(milk_balance(&contract, "alice.near"), 9);
+        assert!(matches!(
+            contract.accounts.get(&1),
+            Some(UpgradableAccount::CheddarAccount(_))
+        ));
+    }
+
+    #[test]
+    fn test_withdraw_deposit() {
+        let (mut context, mut contract) = setup();
+        let alice_id: ValidAccountId = "alice.near".try_into().unwrap();
+        context.predecessor_account_id = "token.cheddar.near".to_string();
+        testing_env!(context.clone());
+        contract.ft_on_transfer(alice_id.clone(), ONE_NEAR.into(), "".to_string());
+
+        context.predecessor_account_id = "alice.near".to_string();
+        testing_env!(context.clone());
+        contract.withdraw_deposit();
+        let alice = contract.get_account(alice_id.clone()).unwrap();
+        assert_eq!(alice.deposited_cheddar.0, 0);
+        assert_eq!(alice.pending_withdrawal.0, ONE_NEAR);
+
+        // failed transfer: the deposit is restored
+        context.predecessor_account_id = context.current_account_id.clone();
+        testing_env!(
+            context.clone(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![PromiseResult::Failed]
+        );
+        contract.transfer_callback("alice.near".to_string(), ONE_NEAR.into());
+        let alice = contract.get_account(alice_id.clone()).unwrap();
+        assert_eq!(alice.deposited_cheddar.0, ONE_NEAR);
+        assert_eq!(alice.pending_withdrawal.0, 0);
+
+        context.predecessor_account_id = "alice.near".to_string();
+        testing_env!(context.clone());
+        contract.withdraw_deposit();
+
+        // successful transfer: the empty account is removed
+        context.predecessor_account_id = context.current_account_id.clone();
+        testing_env!(
+            context.clone(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![])]
+        );
+        contract.transfer_callback("alice.near".to_string(), ONE_NEAR.into());
+        assert!(!contract.account_exists(alice_id));
+    }
+
+    #[test]
+    fn test_delete_account_and_reuse_index() {
+        let (mut context, mut contract) = setup();
+        context.predecessor_account_id = "admin.cheddar.near".to_string();
+        testing_env!(context.clone());
+        contract.set_pixel_pricing(0, 0);
+        context.predecessor_account_id = "alice.near".to_string();
+        testing_env!(context.clone());
+        contract.draw(vec![pixel(1, 1, 0xff0000), pixel(2, 1, 0xff0000)]);
+        context.block_timestamp += 60 * FROM_NANO;
+        context.predecessor_account_id = "bob.near".to_string();
+        testing_env!(context.clone());
+        contract.draw(vec![pixel(1, 1, 0x00ff00), pixel(2, 1, 0x00ff00)]);
+
+        // alice lost her pixels and withdraws all the farmed Cheddar
+        context.predecessor_account_id = "alice.near".to_string();
+        testing_env!(context.clone());
+        let alice_index = contract
+            .account_indices
+            .get(&"alice.near".to_string())
+            .unwrap();
+        contract.withdraw_crop(None, None);
+
+        context.predecessor_account_id = context.current_account_id.clone();
+        testing_env!(
+            context.clone(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![])]
+        );
+        let farmed = contract.get_pending_withdrawal("alice.near".try_into().unwrap());
+        contract.mint_callback("alice.near".to_string(), farmed, Some(true));
+        assert!(!contract.account_exists("alice.near".try_into().unwrap()));
+        assert!(contract.get_account_by_index(alice_index).is_none());
+        assert_eq!(contract.mint_funded, 0);
+        assert_eq!(contract.stats().num_accounts, 2);
+
+        // the freed index is reused
+        context.predecessor_account_id = "carol.near".to_string();
+        testing_env!(context.clone());
+        contract.register_account();
+        assert_eq!(
+            contract.get_account_id_by_index(alice_index),
+            Some("carol.near".to_string())
+        );
+        assert_eq!(contract.get_num_accounts(), 3);
+    }
+
+    #[test]
+    fn test_accounts_and_leaderboards() {
+        let (mut context, mut contract) = setup();
+        context.predecessor_account_id = "admin.cheddar.near".to_string();
+        testing_env!(context.clone());
+        contract.set_pixel_pricing(0, 0);
+        context.predecessor_account_id = "alice.near".to_string();
+        testing_env!(context.clone());
+        contract.draw(vec![pixel(1, 1, 0xff0000), pixel(2, 1, 0xff0000)]);
+        context.predecessor_account_id = "bob.near".to_string();
+        testing_env!(context.clone());
+        contract.draw(vec![pixel(3, 1, 0x00ff00)]);
+
+        let accounts = contract.get_accounts(1, 10);
+        assert_eq!(accounts.len(), 2);
+        assert_eq!(accounts[0].account_id, "alice.near");
+        assert_eq!(contract.get_accounts(2, 10).len(), 1);
+
+        let top = contract.get_leaderboard(LeaderboardKind::Pixels);
+        assert_eq!(top[0].account_id, "alice.near");
+        assert_eq!(top[0].score.0, 2);
+        assert_eq!(top[1].account_id, "bob.near");
+
+        // bob takes over alice's pixels
+        contract.draw(vec![pixel(1, 1, 0x00ff00)]);
+        let top = contract.get_leaderboard(LeaderboardKind::Pixels);
+        assert_eq!(top[0].account_id, "bob.near");
+        let top = contract.get_leaderboard(LeaderboardKind::MilkSpent);
+        assert_eq!(top.len(), 2);
+        assert!(top.iter().all(|e| e.score.0 == 2));
+
+        context.block_timestamp += 60 * FROM_NANO;
+        testing_env!(context.clone());
+        contract.register_account();
+        let top = contract.get_leaderboard(LeaderboardKind::FarmedCheddar);
+        assert_eq!(top.len(), 1);
+        assert_eq!(top[0].account_id, "bob.near");
+    }
+
+    #[test]
+    fn test_sorted_leaderboard() {
+        testing_env!(get_context(STARTS * FROM_NANO, false));
+        let mut leaderboard = SortedLeaderboard::new(b"x");
+        let size = LEADERBOARD_SIZE as u32;
+        for i in 1..=size + 1 {
+            leaderboard.update(i, Balance::from(10 + i));
+        }
+        let top = leaderboard.top();
+        assert_eq!(top.len(), LEADERBOARD_SIZE);
+        assert_eq!(top[0], (size + 1, Balance::from(size + 11)));
+        assert!(top.iter().all(|(i, _)| *i != 1));
+
+        // the first account outside of the list moves up
+        leaderboard.update(size + 1, 1);
+        let top = leaderboard.top();
+        assert_eq!(top[0], (size, Balance::from(size + 10)));
+        assert_eq!(top[LEADERBOARD_SIZE - 1], (1, 11));
+        leaderboard.remove(size);
+        assert_eq!(leaderboard.top()[0], (size - 1, Balance::from(size + 9)));
+    }
+
+    #[test]
+    fn test_account_stats() {
+        let (mut context, mut contract) = setup();
+        context.predecessor_account_id = "admin.cheddar.near".to_string();
+        testing_env!(context.clone());
+        contract.set_pixel_pricing(0, 0);
+        context.predecessor_account_id = "alice.near".to_string();
+        context.attached_deposit = ONE_NEAR;
+        testing_env!(context.clone());
+        contract.buy_tokens();
+        contract.draw(vec![pixel(1, 1, 0xff0000), pixel(2, 1, 0xff0000)]);
+
+        context.predecessor_account_id = "bob.near".to_string();
+        context.attached_deposit = 0;
+        context.block_timestamp += 60 * FROM_NANO;
+        testing_env!(context.clone());
+        contract.draw(vec![pixel(1, 1, 0x00ff00)]);
+
+        let alice = contract
+            .get_account_stats("alice.near".try_into().unwrap())
+            .unwrap();
+        assert!(alice.milk_bought_with_near.0 > 0);
+        assert_eq!(alice.milk_spent.0, 2);
+        assert_eq!(alice.pixels_painted, 2);
+        assert_eq!(alice.pixels_lost, 1);
+        assert!(alice.farmed_cheddar.0 > 0);
+        assert_eq!(alice.last_activity, alice.first_activity);
+
+        let bob = contract
+            .get_account_stats("bob.near".try_into().unwrap())
+            .unwrap();
+        assert_eq!(bob.pixels_painted, 1);
+        assert_eq!(bob.pixels_lost, 0);
+        assert_eq!(bob.first_activity, alice.first_activity + 60);
+        assert!(contract
+            .get_account_stats("carol.near".try_into().unwrap())
+            .is_none());
+    }
+
+    #[test]
+    fn test_daily_stats() {
+        let (mut context, mut contract) = setup();
+        contract.set_pixel_pricing(0, 0);
+        let day = context.block_timestamp / DAY;
+        context.predecessor_account_id = "alice.near".to_string();
+        testing_env!(context.clone());
+        contract.draw(vec![pixel(1, 1, 0xff0000), pixel(2, 1, 0xff0000)]);
+        context.predecessor_account_id = "bob.near".to_string();
+        testing_env!(context.clone());
+        contract.draw(vec![pixel(3, 1, 0xff0000)]);
+        contract.draw(vec![pixel(4, 1, 0xff0000)]);
+
+        context.block_timestamp += DAY;
+        context.attached_deposit = ONE_NEAR;
+        testing_env!(context.clone());
+        contract.buy_tokens();
+
+        let stats = contract.get_daily_stats(0, 10);
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].day, day);
+        assert_eq!(stats[0].pixels_drawn, 4);
+        assert_eq!(stats[0].milk_used.0, 4);
+        assert_eq!(stats[0].active_accounts, 2);
+        assert_eq!(stats[1].day, day + 1);
+        assert_eq!(stats[1].near_received.0, ONE_NEAR);
+        assert!(stats[1].milk_bought.0 > 0);
+        assert!(stats[1].farmed_cheddar.0 > 0);
+        assert_eq!(stats[1].active_accounts, 1);
+
+        let stats = contract.get_daily_stats(day + 1, 10);
+        assert_eq!(stats.len(), 1);
+        assert_eq!(contract.get_daily_stats(0, 1)[0].day, day);
+    }
+
+    #[test]
+    fn test_board_stats() {
+        let (mut context, mut contract) = setup();
+        context.predecessor_account_id = "admin.cheddar.near".to_string();
+        testing_env!(context.clone());
+        contract.set_pixel_pricing(0, 0);
+        context.predecessor_account_id = "alice.near".to_string();
+        testing_env!(context.clone());
+        contract.draw(vec![pixel(1, 1, 0xff0000), pixel(2, 1, 0xff0000)]);
+        context.predecessor_account_id = "bob.near".to_string();
+        testing_env!(context.clone());
+        contract.draw(vec![pixel(1, 1, 0x00ff00)]);
+
+        let stats = contract.board_stats();
+        assert_eq!(stats.num_owners, 2);
+        assert_eq!(stats.contract_pixels, TOTAL_NUM_PIXELS - 2);
+        assert_eq!(stats.color_buckets[color_bucket(0xff0000)], 1);
+        assert_eq!(stats.color_buckets[color_bucket(0x00ff00)], 1);
+        assert_eq!(
+            stats.color_buckets[color_bucket(0xffffff)],
+            TOTAL_NUM_PIXELS - 2
+        );
+
+        // alice loses her last pixel
+        contract.draw(vec![pixel(2, 1, 0x00ff00)]);
+        let stats = contract.board_stats();
+        assert_eq!(stats.num_owners, 1);
+        assert_eq!(stats.color_buckets[color_bucket(0xff0000)], 0);
+        assert_eq!(stats.color_buckets[color_bucket(0x00ff00)], 2);
+
+        context.predecessor_account_id = "admin.cheddar.near".to_string();
+        testing_env!(context.clone());
+        contract.reset_board();
+        let stats = contract.board_stats();
+        assert_eq!(stats.num_owners, 0);
+        assert_eq!(stats.contract_pixels, TOTAL_NUM_PIXELS);
+    }
+
+    #[test]
+    fn test_delete_board() {
+        let (mut context, mut contract) = setup();
+        contract.set_pixel_pricing(0, 0);
+        contract.add_team("red".to_string());
+        context.predecessor_account_id = "alice.near".to_string();
+        testing_env!(context.clone());
+        contract.join_team(0);
+        contract.draw(vec![pixel(1, 1, 0xff0000), pixel(2, 1, 0xff0000)]);
+        context.predecessor_account_id = "bob.near".to_string();
+        testing_env!(context.clone());
+        contract.draw(vec![pixel(3, 1, 0x00ff00)]);
+
+        context.block_timestamp += 60 * FROM_NANO;
+        context.predecessor_account_id = "admin.cheddar.near".to_string();
+        testing_env!(context.clone());
+        contract.delete_board();
+        let stats = contract.board_stats();
+        assert_eq!(stats.num_owners, 0);
+        assert_eq!(stats.contract_pixels, TOTAL_NUM_PIXELS);
+        assert_eq!(
+            stats.color_buckets[color_bucket(0xffffff)],
+            TOTAL_NUM_PIXELS
+        );
+        for id in ["alice.near", "bob.near"] {
+            let a = contract.get_account(id.try_into().unwrap()).unwrap();
+            assert_eq!(a.num_pixels, 0);
+            assert_eq!(a.declared_value.0, 0);
+        }
+        assert_eq!(contract.get_team_scores()[0].territory, 0);
+    }
+
+    #[test]
+    fn test_teams() {
+        let (mut context, mut contract) = setup();
+        contract.set_pixel_pricing(0, 0);
+        assert_eq!(contract.add_team("red".to_string()), 0);
+        assert_eq!(contract.add_team("green".to_string()), 1);
+        context.predecessor_account_id = "token.cheddar.near".to_string();
+        testing_env!(context.clone());
+        contract.ft_on_transfer(
+            "admin.cheddar.near".try_into().unwrap(),
+            (3 * ONE_NEAR).into(),
+            r#"{"action": "fund_team_bonus"}"#.to_string(),
+        );
+
+        context.predecessor_account_id = "alice.near".to_string();
+        testing_env!(context.clone());
+        contract.draw(vec![pixel(1, 1, 0xff0000), pixel(2, 1, 0xff0000)]);
+        contract.join_team(0);
+        context.predecessor_account_id = "bob.near".to_string();
+        testing_env!(context.clone());
+        contract.join_team(1);
+        contract.draw(vec![pixel(1, 1, 0x00ff00)]);
+
+        let scores = contract.get_team_scores();
+        assert_eq!(scores[0].territory, 1);
+        assert_eq!(scores[1].territory, 1);
+        assert_eq!(scores[1].members, 1);
+        assert_eq!(scores[0].bonus.0, 3 * ONE_NEAR / 2);
+
+        contract.draw(vec![pixel(3, 1, 0x00ff00)]);
+        let scores = contract.get_team_scores();
+        assert_eq!(scores[1].territory, 2);
+        assert_eq!(scores[1].bonus.0, 2 * ONE_NEAR);
+
+        context.block_timestamp = (ENDS + 1) * FROM_NANO;
+        testing_env!(context.clone());
+        assert_eq!(contract.claim_team_bonus().0, 2 * ONE_NEAR);
+        assert_eq!(
+            contract
+                .get_account("bob.near".try_into().unwrap())
+                .unwrap()
+                .deposited_cheddar
+                .0,
+            2 * ONE_NEAR
+        );
+        context.predecessor_account_id = "alice.near".to_string();
+        testing_env!(context.clone());
+        assert_eq!(contract.claim_team_bonus().0, ONE_NEAR);
+    }
+
+    #[test]
+    #[should_panic(expected = "Team bonus already claimed")]
+    fn test_team_bonus_claimed_once() {
+        let (mut context, mut contract) = setup();
+        contract.add_team("red".to_string());
+        context.predecessor_account_id = "alice.near".to_string();
+        testing_env!(context.clone());
+        contract.join_team(0);
+        context.block_timestamp = (ENDS + 2) * FROM_NANO;
+        testing_env!(context.clone());
+        contract.claim_team_bonus();
+
+        // moving the end of the game doesn't allow to claim again
+        context.predecessor_account_id = "admin.cheddar.near".to_string();
+        testing_env!(context.clone());
+        contract.set_start_end(STARTS, ENDS + 1);
+        context.predecessor_account_id = "alice.near".to_string();
+        testing_env!(context.clone());
+        contract.claim_team_bonus();
+    }
+
+    #[test]
+    fn test_close_season() {
+        let (mut context, mut contract) = setup();
+        contract.set_pixel_pricing(0, 0);
+        contract.add_team("red".to_string());
+        context.predecessor_account_id = "token.cheddar.near".to_string();
+        testing_env!(context.clone());
+        contract.ft_on_transfer(
+            "admin.cheddar.near".try_into().unwrap(),
+            (3 * ONE_NEAR).into(),
+            r#"{"action": "fund_season_prize"}"#.to_string(),
+        );
+        contract.ft_on_transfer(
+            "admin.cheddar.near".try_into().unwrap(),
+            ONE_NEAR.into(),
+            r#"{"action": "fund_team_bonus"}"#.to_string(),
+        );
+        context.predecessor_account_id = "alice.near".to_string();
+        testing_env!(context.clone());
+        contract.join_team(0);
+        contract.draw(vec![pixel(0, 0, 0xff0000), pixel(1, 0, 0xff0000)]);
+        context.predecessor_account_id = "bob.near".to_string();
+        testing_env!(context.clone());
+        contract.draw(vec![pixel(2, 0, 0x00ff00)]);
+
+        let lines: Vec<u8> = contract
+            .get_lines((0..u32::from(BOARD_HEIGHT)).collect())
+            .into_iter()
+            .flat_map(Vec::<u8>::from)
+            .collect();
+        context.predecessor_account_id = "admin.cheddar.near".to_string();
+        context.block_timestamp = (ENDS + 1) * FROM_NANO;
+        testing_env!(context.clone());
+        let alice_id: ValidAccountId = "alice.near".try_into().unwrap();
+        let farmed = contract
+            .get_account(alice_id.clone())
+            .unwrap()
+            .banana_balance
+            .0;
+        // one account per call
+        assert!(!contract.settle_season(1));
+        while !contract.settle_season(1) {}
+        assert_eq!(contract.close_season(ENDS + 10, ENDS + 1000), 1);
+
+        let season = contract.get_season(0).unwrap();
+        assert_eq!(season.board_hash.0, env::sha256(&lines));
+        assert_eq!(
+            season.rankings,
+            vec![("alice.near".to_string(), 2), ("bob.near".to_string(), 1)]
+        );
+        let alice = contract.get_account(alice_id.clone()).unwrap();
+        assert_eq!(alice.banana_balance.0, farmed);
+        assert_eq!(alice.num_pixels, 0);
+        // the prize and the unclaimed team bonus are paid from the funds
+        assert_eq!(alice.deposited_cheddar.0, 3 * ONE_NEAR);
+        let alice_stats = contract.get_account_stats(alice_id).unwrap();
+        assert_eq!(alice_stats.prizes.0, 3 * ONE_NEAR);
+        assert_eq!(contract.stats().paid_prizes.0, 4 * ONE_NEAR);
+        assert_eq!(contract.prize_funds, 0);
+        assert_eq!(contract.get_team_scores()[0].bonus.0, 0);
+
+        let line = &contract.get_season_lines(0, vec![0])[0].0;
+        let runs: Vec<(u8, Pixel)> = BorshDeserialize::try_from_slice(line).unwrap();
+        assert_eq!(runs.len(), 3);
+        assert_eq!(runs[0].0, 2);
+        assert_eq!(runs[0].1.color, 0xff0000);
+        assert_eq!(runs[2].0, BOARD_WIDTH - 3);
+
+        assert_eq!(contract.get_current_season(), 1);
+        assert!(contract.get_season(1).is_none());
+        assert_eq!(contract.get_settings().start_date, ENDS + 10);
+        assert_eq!(contract.board_stats().contract_pixels, TOTAL_NUM_PIXELS);
+    }
+
+    #[test]
+    #[should_panic(expected = "Season is not settled yet")]
+    fn test_close_season_not_settled() {
+        let (mut context, mut contract) = setup();
+        context.predecessor_account_id = "alice.near".to_string();
+        testing_env!(context.clone());
+        contract.draw(vec![pixel(0, 0, 0xff0000)]);
+        context.predecessor_account_id = "admin.cheddar.near".to_string();
+        context.block_timestamp = (ENDS + 1) * FROM_NANO;
+        testing_env!(context.clone());
+        contract.settle_season(1);
+        contract.close_season(ENDS + 10, ENDS + 1000);
+    }
+
+    #[test]
+    fn test_close_season_carries_unpaid_prizes() {
+        let (mut context, mut contract) = setup();
+        contract.add_team("red".to_string());
+        context.predecessor_account_id = "token.cheddar.near".to_string();
+        testing_env!(context.clone());
+        contract.ft_on_transfer(
+            "admin.cheddar.near".try_into().unwrap(),
+            (3 * ONE_NEAR).into(),
+            r#"{"action": "fund_season_prize"}"#.to_string(),
+        );
+        contract.ft_on_transfer(
+            "admin.cheddar.near".try_into().unwrap(),
+            ONE_NEAR.into(),
+            r#"{"action": "fund_team_bonus"}"#.to_string(),
+        );
+        context.predecessor_account_id = "alice.near".to_string();
+        testing_env!(context.clone());
+        contract.join_team(0);
+
+        // nobody owns pixels, nothing is paid
+        context.predecessor_account_id = "admin.cheddar.near".to_string();
+        context.block_timestamp = (ENDS + 1) * FROM_NANO;
+        testing_env!(context.clone());
+        assert!(contract.settle_season(100));
+        contract.close_season(ENDS + 10, ENDS + 1000);
+        assert_eq!(contract.get_season(0).unwrap().prize.0, 0);
+        assert_eq!(contract.season_prize, 3 * ONE_NEAR);
+        assert_eq!(contract.team_bonus.pool, ONE_NEAR);
+        assert_eq!(contract.prize_funds, 4 * ONE_NEAR);
+        assert_eq!(contract.team_bonus.paid, 0);
+    }
+
+    #[test]
+    fn test_templates() {
+        let (mut context, mut contract) = setup();
+        contract.set_pixel_pricing(0, 0);
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width: 2,
+            height: 1,
+        };
+        let id =
+            contract.post_template(rect, vec![0xff0000, 0xff0000], (3 * ONE_NEAR).into(), ENDS);
+        let alice_id: ValidAccountId = "alice.near".try_into().unwrap();
+        let bob_id: ValidAccountId = "bob.near".try_into().unwrap();
+
+        context.predecessor_account_id = "alice.near".to_string();
+        testing_env!(context.clone());
+        contract.draw(vec![pixel(0, 0, 0xff0000)]);
+        context.predecessor_account_id = "bob.near".to_string();
+        testing_env!(context.clone());
+        context.attached_deposit = ONE_NEAR;
+        testing_env!(context.clone());
+        contract.buy_tokens();
+        context.attached_deposit = 0;
+        testing_env!(context.clone());
+        // alice's contribution is taken back when the pixel stops matching
+        contract.draw(vec![pixel(0, 0, 0x00ff00)]);
+        assert_eq!(contract.get_template_contribution(id, alice_id.clone()), 0);
+        contract.draw(vec![pixel(0, 0, 0xff0000)]);
+        // repainting cycles don't add contributions
+        contract.draw(vec![pixel(0, 0, 0x00ff00), pixel(0, 0, 0xff0000)]);
+        assert_eq!(contract.get_templates(0, 10)[0].matching, 1);
+        assert_eq!(contract.get_template_contribution(id, bob_id.clone()), 1);
+        context.predecessor_account_id = "alice.near".to_string();
+        testing_env!(context.clone());
+        contract.draw(vec![pixel(1, 0, 0xff0000)]);
+
+        let t = &contract.get_templates(0, 10)[0];
+        assert_eq!(t.matching, 2);
+        assert_eq!(t.contributions, 2);
+        assert!(t.completed_at > 0);
+        assert_eq!(contract.get_template_contribution(id, alice_id.clone()), 1);
+        assert_eq!(contract.get_template_contribution(id, bob_id), 1);
+        assert!(contract.active_templates.is_empty());
+
+        let farmed = contract
+            .get_account(alice_id.clone())
+            .unwrap()
+            .banana_balance
+            .0;
+        assert_eq!(contract.claim_template_reward(id).0, 3 * ONE_NEAR / 2);
+        assert_eq!(
+            contract.get_account(alice_id).unwrap().banana_balance.0,
+            farmed + 3 * ONE_NEAR / 2
+        );
+        assert!(!contract.template_pixel_contributors.contains_key(&(id, 0)));
+        assert!(!contract.template_pixel_contributors.contains_key(&(id, 1)));
+        context.predecessor_account_id = "bob.near".to_string();
+        testing_env!(context.clone());
+        assert_eq!(contract.claim_template_reward(id).0, 3 * ONE_NEAR / 2);
+    }
+
+    #[test]
+    fn test_template_reward_for_deleted_account() {
+        let (mut context, mut contract) = setup();
+        contract.set_pixel_pricing(0, 0);
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width: 1,
+            height: 1,
+        };
+        let id = contract.post_template(rect, vec![0xff0000], ONE_NEAR.into(), ENDS);
+        context.predecessor_account_id = "alice.near".to_string();
+        testing_env!(context.clone());
+        contract.draw(vec![pixel(0, 0, 0xff0000), pixel(5, 5, 0xff0000)]);
+        context.block_timestamp += 10 * FROM_NANO;
+        context.predecessor_account_id = "bob.near".to_string();
+        testing_env!(context.clone());
+        contract.draw(vec![pixel(0, 0, 0xff0000), pixel(5, 5, 0x00ff00)]);
+
+        // alice withdraws everything and her account is deleted
+        context.predecessor_account_id = "alice.near".to_string();
+        testing_env!(context.clone());
+        contract.withdraw_crop(None, None);
+        context.predecessor_account_id = context.current_account_id.clone();
+        testing_env!(
+            context.clone(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![])]
+        );
+        let farmed = contract.get_pending_withdrawal("alice.near".try_into().unwrap());
+        contract.mint_callback("alice.near".to_string(), farmed, Some(true));
+        let alice_id: ValidAccountId = "alice.near".try_into().unwrap();
+        assert!(!contract.account_exists(alice_id.clone()));
+
+        context.predecessor_account_id = "alice.near".to_string();
+        testing_env!(context.clone());
+        assert_eq!(contract.claim_template_reward(id).0, ONE_NEAR);
+        let alice = contract.get_account(alice_id).unwrap();
+        assert_eq!(alice.banana_balance.0, ONE_NEAR);
+    }
+
+    #[test]
+    #[should_panic(expected = "Template is not finished yet")]
+    fn test_template_not_finished() {
+        let (mut context, mut contract) = setup();
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width: 2,
+            height: 1,
+        };
+        let id = contract.post_template(rect, vec![0xff0000, 0xff0000], ONE_NEAR.into(), ENDS);
+        context.predecessor_account_id = "alice.near".to_string();
+        testing_env!(context.clone());
+        contract.draw(vec![pixel(0, 0, 0xff0000)]);
+        contract.claim_template_reward(id);
+    }
+
+    #[test]
+    fn test_weapons() {
+        let (mut context, mut contract) = setup();
+        context.predecessor_account_id = "admin.cheddar.near".to_string();
+        testing_env!(context.clone());
+        contract.set_pixel_pricing(0, 0);
+        context.predecessor_account_id = "alice.near".to_string();
+        context.attached_deposit = ONE_NEAR;
+        testing_env!(context.clone());
+        contract.buy_tokens();
+        let alice_id: ValidAccountId = "alice.near".try_into().unwrap();
+        let milk = milk_balance(&contract, "alice.near");
+
+        let fill = Weapon::FillRect {
+            rect: Rect {
+                x: 0,
+                y: 0,
+                width: 4,
+                height: 5,
+            },
+            color: 0xff0000,
+        };
+        // 20 pixels + 20% surcharge
+        assert_eq!(contract.quote_weapon(alice_id.clone(), fill.clone()).0, 24);
+        contract.use_weapon(fill);
+        assert_eq!(milk_balance(&contract, "alice.near"), milk - 24);
+        assert_eq!(contract.get_account_num_pixels(alice_id.clone()), 20);
+
+        // a circle in the corner is clipped to the board
+        let circle = Weapon::Circle {
+            x: 0,
+            y: 0,
+            radius: 1,
+            color: 0x00ff00,
+        };
+        assert_eq!(circle.pixels().len(), 3);
+
+        context.predecessor_account_id = "bob.near".to_string();
+        testing_env!(context.clone());
+        contract.buy_tokens();
+        contract.use_weapon(Weapon::Bomb {
+            x: 1,
+            y: 1,
+            radius: 1,
+        });
+        // bombed pixels go back to the contract
+        assert_eq!(contract.get_account_num_pixels(alice_id), 15);
+        let bob_id: ValidAccountId = "bob.near".try_into().unwrap();
+        assert_eq!(contract.get_account_num_pixels(bob_id), 0);
+        assert_eq!(
+            contract.board_stats().contract_pixels,
+            TOTAL_NUM_PIXELS - 15
+        );
+        assert_eq!(
+            contract.board_stats().color_buckets[color_bucket(0xff0000)],
+            15
+        );
+    }
+
+    #[test]
+    fn test_bomb_own_shielded_pixels() {
+        let (mut context, mut contract) = setup();
+        contract.set_pixel_pricing(0, 0);
+        context.predecessor_account_id = "alice.near".to_string();
+        context.attached_deposit = ONE_NEAR;
+        testing_env!(context.clone());
+        contract.buy_tokens();
+        contract.draw(vec![pixel(1, 1, 0xff0000)]);
+        let rect = Rect {
+            x: 1,
+            y: 1,
+            width: 1,
+            height: 1,
+        };
+        contract.protect(rect, 60 * 60);
+        contract.use_weapon(Weapon::Bomb {
+            x: 1,
+            y: 1,
+            radius: 0,
+        });
+        assert_eq!(contract.board.get_line(1).0[1].owner_id, 0);
+        assert_eq!(
+            contract.get_account_num_pixels("alice.near".try_into().unwrap()),
+            0
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Weapon radius is too large")]
+    fn test_weapon_radius_too_large() {
+        Weapon::Circle {
+            x: 40,
+            y: 40,
+            radius: 255,
+            color: 0xff0000,
+        }
+        .pixels();
+    }
+
+    #[test]
+    fn test_weapon_quote_with_harberger() {
+        let (mut context, mut contract) = setup();
+        contract.set_pixel_pricing(0, 0);
+        contract.set_harberger_tax_rate(10_000);
+        context.predecessor_account_id = "alice.near".to_string();
+        testing_env!(context.clone());
+        let mut request = pixel(1, 1, 0xff0000);
+        request.price = Some(1);
+        contract.draw(vec![request]);
+
+        context.predecessor_account_id = "bob.near".to_string();
+        context.attached_deposit = ONE_NEAR;
+        testing_env!(context.clone());
+        contract.buy_tokens();
+        let bomb = Weapon::Bomb {
+            x: 1,
+            y: 1,
+            radius: 1,
+        };
+        let milk = milk_balance(&contract, "bob.near");
+        // 5 pixels, 1 Milk declared price of alice's pixel and 20% surcharge
+        let quote = contract.quote_weapon("bob.near".try_into().unwrap(), bomb.clone());
+        assert_eq!(quote.0, 8);
+        contract.use_weapon(bomb);
+        assert_eq!(milk_balance(&contract, "bob.near"), milk - 8);
+    }
+
+    #[test]
+    fn test_draw_limits() {
+        let (mut context, mut contract) = setup();
+        contract.set_pixel_pricing(0, 0);
+        contract.set_draw_limits(DrawLimits {
+            max_pixels_per_block: 0,
+            max_pixels_per_hour: 3,
+            cooldown: 60,
+        });
+        let alice_id: ValidAccountId = "alice.near".try_into().unwrap();
+        context.predecessor_account_id = "alice.near".to_string();
+        context.attached_deposit = ONE_NEAR;
+        testing_env!(context.clone());
+        contract.buy_tokens();
+        let cooldown = contract.get_cooldown(alice_id.clone());
+        assert_eq!(cooldown.next_draw_at, STARTS);
+        assert_eq!(cooldown.pixels_left_this_hour, Some(3));
+        assert_eq!(cooldown.pixels_left_this_block, None);
+
+        contract.draw(vec![pixel(1, 1, 0xff0000), pixel(2, 1, 0xff0000)]);
+        let cooldown = contract.get_cooldown(alice_id.clone());
+        assert_eq!(cooldown.next_draw_at, STARTS + 60);
+        assert_eq!(cooldown.pixels_left_this_hour, Some(1));
+
+        context.block_timestamp += 60 * FROM_NANO;
+        testing_env!(context.clone());
+        contract.draw(vec![pixel(3, 1, 0xff0000)]);
+        // the hour limit is reached
+        assert_eq!(
+            contract.get_cooldown(alice_id.clone()).next_draw_at,
+            STARTS + 3600
+        );
+
+        context.block_timestamp = (STARTS + 3600) * FROM_NANO;
+        testing_env!(context.clone());
+        contract.draw(vec![pixel(4, 1, 0xff0000)]);
+        assert_eq!(
+            contract
+                .get_cooldown(alice_id.clone())
+                .pixels_left_this_hour,
+            Some(2)
+        );
+
+        context.predecessor_account_id = "admin.cheddar.near".to_string();
+        testing_env!(context.clone());
+        contract.set_draw_limits(DrawLimits {
+            max_pixels_per_block: 0,
+            max_pixels_per_hour: 0,
+            cooldown: u64::MAX,
+        });
+        assert_eq!(
+            contract.get_cooldown(alice_id).next_draw_at,
+            u64::MAX / FROM_NANO
+        );
+    }
+
+    /// Alice with enough Milk and at most 2 pixels per block.
+    fn setup_block_limit() -> (VMContext, Place) {
+        let (mut context, mut contract) = setup();
+        contract.set_pixel_pricing(0, 0);
+        contract.set_draw_limits(DrawLimits {
+            max_pixels_per_block: 2,
+            max_pixels_per_hour: 0,
+            cooldown: 0,
+        });
+        context.predecessor_account_id = "alice.near".to_string();
+        context.attached_deposit = ONE_NEAR;
+        testing_env!(context.clone());
+        contract.buy_tokens();
+        context.attached_deposit = 0;
+        testing_env!(context.clone());
+        (context, contract)
+    }
+
+    #[test]
+    #[should_panic(expected = "Too many pixels drawn in this block")]
+    fn test_draw_limits_weapon() {
+        let (_, mut contract) = setup_block_limit();
+        contract.use_weapon(Weapon::Circle {
+            x: 0,
+            y: 0,
+            radius: 1,
+            color: 0xff0000,
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "Too many pixels drawn in this block")]
+    fn test_draw_limits_reveal() {
+        let (mut context, mut contract) = setup_block_limit();
+        let pixels = vec![
+            pixel(1, 1, 0xff0000),
+            pixel(2, 1, 0xff0000),
+            pixel(3, 1, 0xff0000),
+        ];
+        commit(&mut contract, &pixels, 3);
+        context.block_index += 1;
+        testing_env!(context.clone());
+        contract.reveal_draw(pixels, "salt".to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "Too many pixels drawn in this block")]
+    fn test_draw_limits_ft_on_transfer() {
+        let (mut context, mut contract) = setup_block_limit();
+        context.predecessor_account_id = "token.cheddar.near".to_string();
+        testing_env!(context.clone());
+        contract.ft_on_transfer(
+            "alice.near".try_into().unwrap(),
+            ONE_NEAR.into(),
+            r#"{"action": "draw", "pixels": [
+                {"x": 1, "y": 1, "color": 255},
+                {"x": 2, "y": 1, "color": 255},
+                {"x": 3, "y": 1, "color": 255}]}"#
+                .to_string(),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Draw cooldown is not over yet")]
+    fn test_draw_cooldown() {
+        let (mut context, mut contract) = setup();
+        contract.set_draw_limits(DrawLimits {
+            max_pixels_per_block: 0,
+            max_pixels_per_hour: 0,
+            cooldown: 60,
+        });
+        context.predecessor_account_id = "alice.near".to_string();
+        testing_env!(context.clone());
+        contract.draw(vec![pixel(1, 1, 0xff0000)]);
+        context.block_timestamp += 59 * FROM_NANO;
+        testing_env!(context.clone());
+        contract.draw(vec![pixel(2, 1, 0xff0000)]);
+    }
+
+    fn commit(contract: &mut Place, pixels: &[SetPixelRequest], milk: u128) {
+        contract.commit_draw(commitment_hash(pixels, "salt").into(), milk.into());
+    }
+
+    #[test]
+    fn test_commit_reveal_draw() {
+        let (mut context, mut contract) = setup();
+        context.predecessor_account_id = "admin.cheddar.near".to_string();
+        testing_env!(context.clone());
+        contract.set_pixel_pricing(0, 0);
+        context.predecessor_account_id = "alice.near".to_string();
+        context.attached_deposit = ONE_NEAR;
+        testing_env!(context.clone());
+        contract.buy_tokens();
+        let milk = milk_balance(&contract, "alice.near");
+        let pixels = vec![pixel(1, 1, 0xff0000), pixel(2, 1, 0xff0000)];
+        commit(&mut contract, &pixels, 10);
+        assert_eq!(milk_balance(&contract, "alice.near"), milk - 10);
+        let alice_id: ValidAccountId = "alice.near".try_into().unwrap();
+        assert_eq!(
+            contract
+                .get_draw_commitment(alice_id.clone())
+                .unwrap()
+                .expires_at,
+            STARTS + 600
+        );
+
+        context.block_index += 1;
+        context.block_timestamp += 600 * FROM_NANO;
+        testing_env!(context.clone());
+        contract.reveal_draw(pixels, "salt".to_string());
+        assert_eq!(milk_balance(&contract, "alice.near"), milk - 2);
+        assert_eq!(contract.get_account_num_pixels(alice_id.clone()), 2);
+        assert!(contract.get_draw_commitment(alice_id).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "Draw must be revealed in a later block")]
+    fn test_reveal_in_the_same_block() {
+        let (mut context, mut contract) = setup();
+        context.predecessor_account_id = "alice.near".to_string();
+        testing_env!(context.clone());
+        let pixels = vec![pixel(1, 1, 0xff0000)];
+        commit(&mut contract, &pixels, 1);
+        contract.reveal_draw(pixels, "salt".to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "Pixels don't match the commitment")]
+    fn test_reveal_with_another_price() {
+        let (mut context, mut contract) = setup();
+        contract.set_harberger_tax_rate(100);
+        context.predecessor_account_id = "alice.near".to_string();
+        testing_env!(context.clone());
+        let mut request = pixel(1, 1, 0xff0000);
+        request.price = Some(1);
+        commit(&mut contract, &[request], 1);
+        context.block_index += 1;
+        testing_env!(context.clone());
+        let mut request = pixel(1, 1, 0xff0000);
+        request.price = Some(100);
+        contract.reveal_draw(vec![request], "salt".to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "Commitment expired")]
+    fn test_reveal_expired() {
+        let (mut context, mut contract) = setup();
+        context.predecessor_account_id = "alice.near".to_string();
+        testing_env!(context.clone());
+        let pixels = vec![pixel(1, 1, 0xff0000)];
+        commit(&mut contract, &pixels, 1);
+        context.block_index += 1;
+        context.block_timestamp += 601 * FROM_NANO;
+        testing_env!(context.clone());
+        contract.reveal_draw(pixels, "salt".to_string());
+    }
+
+    #[test]
+    fn test_refund_expired_commitment() {
+        let (mut context, mut contract) = setup();
+        context.predecessor_account_id = "alice.near".to_string();
+        testing_env!(context.clone());
+        commit(&mut contract, &[pixel(1, 1, 0xff0000)], 2);
+        assert_eq!(milk_balance(&contract, "alice.near"), 0);
+
+        context.block_timestamp += 601 * FROM_NANO;
+        testing_env!(context.clone());
+        assert_eq!(contract.refund_draw_commitment().0, 2);
+        assert_eq!(milk_balance(&contract, "alice.near"), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "Pixels don't match the commitment")]
+    fn test_reveal_other_pixels() {
+        let (mut context, mut contract) = setup();
+        context.predecessor_account_id = "alice.near".to_string();
+        testing_env!(context.clone());
+        commit(&mut contract, &[pixel(1, 1, 0xff0000)], 1);
+        context.block_index += 1;
+        testing_env!(context.clone());
+        contract.reveal_draw(vec![pixel(1, 1, 0x00ff00)], "salt".to_string());
+    }
+
+    #[test]
+    fn test_undo_last_draw() {
+        let (mut context, mut contract) = setup();
+        contract.set_pixel_pricing(0, 0);
+        contract.set_undo_params(60, 10_000);
+        context.predecessor_account_id = "alice.near".to_string();
+        context.attached_deposit = ONE_NEAR;
+        testing_env!(context.clone());
+        contract.buy_tokens();
+        context.attached_deposit = 0;
+        contract.draw(vec![pixel(1, 1, 0xff0000), pixel(2, 1, 0xff0000)]);
+        context.predecessor_account_id = "bob.near".to_string();
+        testing_env!(context.clone());
+        contract.draw(vec![pixel(1, 1, 0x00ff00), pixel(5, 1, 0x00ff00)]);
+        assert_eq!(milk_balance(&contract, "bob.near"), 0);
+
+        // alice paints over one of bob's pixels, it can't be restored anymore
+        context.predecessor_account_id = "alice.near".to_string();
+        context.block_timestamp += FROM_NANO;
+        testing_env!(context.clone());
+        contract.draw(vec![pixel(5, 1, 0x0000ff)]);
+
+        context.predecessor_account_id = "bob.near".to_string();
+        context.block_timestamp += 30 * FROM_NANO;
+        testing_env!(context.clone());
+        assert_eq!(contract.undo_last_draw().0, 1);
+        assert_eq!(milk_balance(&contract, "bob.near"), 1);
+        let alice_id: ValidAccountId = "alice.near".try_into().unwrap();
+        let bob_id: ValidAccountId = "bob.near".try_into().unwrap();
+        assert_eq!(contract.get_account_num_pixels(alice_id), 3);
+        assert_eq!(contract.get_account_num_pixels(bob_id), 0);
+        let stats = contract.board_stats();
+        assert_eq!(stats.num_owners, 1);
+        assert_eq!(stats.color_buckets[color_bucket(0xff0000)], 2);
+        assert_eq!(stats.color_buckets[color_bucket(0x00ff00)], 0);
+    }
+
+    #[test]
+    fn test_undo_takes_back_template_contributions() {
+        let (mut context, mut contract) = setup();
+        contract.set_pixel_pricing(0, 0);
+        let rect = Rect {
+            x: 1,
+            y: 1,
+            width: 2,
+            height: 1,
+        };
+        let id = contract.post_template(rect, vec![0xff0000, 0xff0000], ONE_NEAR.into(), ENDS);
+        context.predecessor_account_id = "alice.near".to_string();
+        testing_env!(context.clone());
+        contract.draw(vec![pixel(1, 1, 0xff0000)]);
+        let alice_id: ValidAccountId = "alice.near".try_into().unwrap();
+        assert_eq!(contract.get_template_contribution(id, alice_id.clone()), 1);
+
+        contract.undo_last_draw();
+        assert_eq!(contract.get_template_contribution(id, alice_id), 0);
+        let t = &contract.get_templates(0, 1)[0];
+        assert_eq!(t.matching, 0);
+        assert_eq!(t.contributions, 0);
+    }
+
+    #[test]
+    fn test_undo_with_reused_owner_index() {
+        let (mut context, mut contract) = setup();
+        context.predecessor_account_id = "admin.cheddar.near".to_string();
+        testing_env!(context.clone());
+        contract.set_pixel_pricing(0, 0);
+        context.predecessor_account_id = "alice.near".to_string();
+        testing_env!(context.clone());
+        contract.draw(vec![pixel(1, 1, 0xff0000), pixel(2, 1, 0xff0000)]);
+        context.block_timestamp += 10 * FROM_NANO;
+        context.predecessor_account_id = "bob.near".to_string();
+        testing_env!(context.clone());
+        contract.draw(vec![pixel(1, 1, 0x00ff00), pixel(2, 1, 0x00ff00)]);
+
+        // alice is deleted and carol gets her index
+        context.predecessor_account_id = "alice.near".to_string();
+        testing_env!(context.clone());
+        contract.withdraw_crop(None, None);
+        context.predecessor_account_id = context.current_account_id.clone();
+        testing_env!(
+            context.clone(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![])]
+        );
+        let farmed = contract.get_pending_withdrawal("alice.near".try_into().unwrap());
+        contract.mint_callback("alice.near".to_string(), farmed, Some(true));
+        context.predecessor_account_id = "carol.near".to_string();
+        testing_env!(context.clone());
+        contract.register_account();
+
+        // the restored pixels go to the contract, not to carol
+        context.predecessor_account_id = "bob.near".to_string();
+        testing_env!(context.clone());
+        contract.undo_last_draw();
+        assert_eq!(
+            contract.get_account_num_pixels("carol.near".try_into().unwrap()),
+            0
+        );
+        assert_eq!(contract.board_stats().contract_pixels, TOTAL_NUM_PIXELS);
+        assert_eq!(contract.board_stats().num_owners, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Undo window is over")]
+    fn test_undo_window_is_over() {
+        let (mut context, mut contract) = setup();
+        context.predecessor_account_id = "alice.near".to_string();
+        testing_env!(context.clone());
+        contract.draw(vec![pixel(1, 1, 0xff0000)]);
+        context.block_timestamp += 61 * FROM_NANO;
+        testing_env!(context.clone());
+        contract.undo_last_draw();
+    }
+
+    #[test]
+    fn test_expired_undo_record_is_dropped() {
+        let (mut context, mut contract) = setup();
+        context.predecessor_account_id = "alice.near".to_string();
+        testing_env!(context.clone());
+        contract.draw(vec![pixel(1, 1, 0xff0000)]);
+        assert!(contract
+            .undo_records
+            .contains_key(&"alice.near".to_string()));
+
+        context.block_timestamp += 61 * FROM_NANO;
+        testing_env!(context.clone());
+        contract.register_account();
+        assert!(!contract
+            .undo_records
+            .contains_key(&"alice.near".to_string()));
+    }
+
+    #[test]
+    #[should_panic(expected = "Undo window is over")]
+    fn test_undo_disabled_in_the_same_block() {
+        let (mut context, mut contract) = setup();
+        contract.set_undo_params(60, 10_000);
+        context.predecessor_account_id = "alice.near".to_string();
+        testing_env!(context.clone());
+        contract.draw(vec![pixel(1, 1, 0xff0000)]);
+
+        context.predecessor_account_id = "admin.cheddar.near".to_string();
+        testing_env!(context.clone());
+        contract.set_undo_params(0, 10_000);
+        context.predecessor_account_id = "alice.near".to_string();
+        testing_env!(context.clone());
+        contract.undo_last_draw();
+    }
 }
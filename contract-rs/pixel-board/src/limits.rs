@@ -0,0 +1,134 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::ValidAccountId;
+use near_sdk::serde::{Deserialize, Serialize};
+
+use crate::*;
+
+const HOUR: u64 = 60 * 60 * FROM_NANO;
+
+/// Per-account drawing limits. Zero disables a limit.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DrawLimits {
+    pub max_pixels_per_block: u32,
+    /// pixels per fixed one hour window, starting with the first draw
+    pub max_pixels_per_hour: u32,
+    /// minimum time between draws, in seconds
+    pub cooldown: u64,
+}
+
+/// Draws of an account counted against `DrawLimits`.
+#[derive(BorshDeserialize, BorshSerialize, Default)]
+pub struct DrawHistory {
+    /// in nanoseconds
+    pub last_draw: u64,
+    pub block: u64,
+    pub block_pixels: u32,
+    /// start of the current hour window, in nanoseconds
+    pub hour_start: u64,
+    pub hour_pixels: u32,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Cooldown {
+    /// unix timestamp (in seconds) when the account may draw again
+    pub next_draw_at: u64,
+    /// pixels which can still be drawn in the current hour window
+    pub pixels_left_this_hour: Option<u32>,
+    /// pixels which can still be drawn in the current block
+    pub pixels_left_this_block: Option<u32>,
+}
+
+impl DrawHistory {
+    fn block_pixels(&self, block: u64) -> u32 {
+        if self.block == block {
+            self.block_pixels
+        } else {
+            0
+        }
+    }
+
+    /// End of the cooldown after the last draw, in nanoseconds.
+    fn next_draw(&self, limits: &DrawLimits) -> u64 {
+        self.last_draw
+            .saturating_add(limits.cooldown.saturating_mul(FROM_NANO))
+    }
+
+    fn hour_pixels(&self, now: u64) -> u32 {
+        if now < self.hour_start + HOUR {
+            self.hour_pixels
+        } else {
+            0
+        }
+    }
+
+    /// Asserts that `num_pixels` can be drawn now and records the draw.
+    pub fn record(&mut self, limits: &DrawLimits, num_pixels: u32) {
+        let now = env::block_timestamp();
+        let block = env::block_index();
+        if limits.cooldown > 0 && self.last_draw > 0 {
+            assert!(
+                now >= self.next_draw(limits),
+                "Draw cooldown is not over yet"
+            );
+        }
+        let block_pixels = self.block_pixels(block) + num_pixels;
+        assert!(
+            limits.max_pixels_per_block == 0 || block_pixels <= limits.max_pixels_per_block,
+            "Too many pixels drawn in this block"
+        );
+        let hour_pixels = self.hour_pixels(now);
+        if hour_pixels == 0 {
+            self.hour_start = now;
+        }
+        assert!(
+            limits.max_pixels_per_hour == 0
+                || hour_pixels + num_pixels <= limits.max_pixels_per_hour,
+            "Too many pixels drawn in this hour"
+        );
+        self.last_draw = now;
+        self.block = block;
+        self.block_pixels = block_pixels;
+        self.hour_pixels = hour_pixels + num_pixels;
+    }
+}
+
+#[near_bindgen]
+impl Place {
+    /// Returns when the account may draw next and how many pixels it can draw.
+    pub fn get_cooldown(&self, account_id: ValidAccountId) -> Cooldown {
+        let limits = &self.draw_limits;
+        let h = self
+            .get_internal_account_by_id(account_id.as_ref())
+            .map(|a| a.draws)
+            .unwrap_or_default();
+        let now = env::block_timestamp();
+        let hour_pixels = h.hour_pixels(now);
+        let mut next_draw = now;
+        if limits.cooldown > 0 && h.last_draw > 0 {
+            next_draw = std::cmp::max(next_draw, h.next_draw(limits));
+        }
+        if limits.max_pixels_per_hour > 0 && hour_pixels >= limits.max_pixels_per_hour {
+            next_draw = std::cmp::max(next_draw, h.hour_start + HOUR);
+        }
+        let left = |max: u32, used: u32| (max > 0).then(|| max.saturating_sub(used));
+        Cooldown {
+            next_draw_at: next_draw / FROM_NANO,
+            pixels_left_this_hour: left(limits.max_pixels_per_hour, hour_pixels),
+            pixels_left_this_block: left(
+                limits.max_pixels_per_block,
+                h.block_pixels(env::block_index()),
+            ),
+        }
+    }
+
+    pub fn get_draw_limits(&self) -> DrawLimits {
+        self.draw_limits
+    }
+
+    pub fn set_draw_limits(&mut self, limits: DrawLimits) {
+        self.only_admin();
+        self.draw_limits = limits;
+    }
+}
@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::{LookupMap, LookupSet, Vector};
+
+use crate::*;
+
+/// Version of the `Place` layout. The state of the first deployed version has
+/// no version and is migrated by `migrate`. The layout changes of a release
+/// are migrated together: intermediate commits of a release aren't deploy
+/// points. A release changing the layout bumps the version and migrates from
+/// the previous one.
+pub const STATE_VERSION: u32 = 1;
+
+/// Board state of the first deployed version, without pixel metadata.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub(crate) struct OldPixelBoard {
+    pub(crate) lines: Vector<PixelLine>,
+    pub(crate) line_versions: Vec<u32>,
+}
+
+/// Contract state of the first deployed version.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub(crate) struct OldPlace {
+    pub(crate) account_indices: LookupMap<AccountId, u32>,
+    pub(crate) accounts: LookupMap<u32, UpgradableAccount>,
+    pub(crate) num_accounts: u32,
+    pub(crate) board: OldPixelBoard,
+    pub(crate) last_reward_timestamp: u64,
+    /// bought Milk is at `Berry::Milk`
+    pub(crate) bought_balances: Vec<Balance>,
+    pub(crate) used_milk: Balance,
+    pub(crate) farmed_cheddar: Balance,
+    pub(crate) is_active: bool,
+    pub(crate) admin: AccountId,
+    pub(crate) cheddar: AccountId,
+    pub(crate) treasury: AccountId,
+    pub(crate) mint_funded: u32,
+    pub(crate) reward_rate: Balance,
+    pub(crate) milk_price: Balance,
+    pub(crate) blacklist: LookupSet<AccountId>,
+    pub(crate) starts: u64,
+    pub(crate) ends: u64,
+}
+
+#[near_bindgen]
+impl Place {
+    /// Migrates the state of the first deployed version: pixel metadata is
+    /// backfilled with defaults, the board stats and the pixels leaderboard
+    /// are computed from the lines and the new settings get their default
+    /// values. Accounts are converted when loaded, see `UpgradableAccount`.
+    /// Panics if the state isn't the state of the first deployed version.
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        assert_self();
+        let data = env::storage_read(b"STATE").expect("Old state doesn't exist");
+        let old = OldPlace::try_from_slice(&data)
+            .expect("State is not the state of the first deployed version");
+
+        let mut board = PixelBoard {
+            lines: old.board.lines,
+            metas: Vector::new(b"m".to_vec()),
+            line_versions: old.board.line_versions,
+            shields: Vec::new(),
+            color_buckets: vec![0; initial_color_buckets().len()],
+        };
+        let mut owners = HashMap::<AccountIndex, u32>::new();
+        let default_meta = PixelMetaLine::default();
+        for i in 0..BOARD_HEIGHT {
+            for p in board.get_line(u32::from(i)).0 {
+                board.color_buckets[color_bucket(p.color)] += 1;
+                *owners.entry(p.owner_id).or_default() += 1;
+            }
+            board.metas.push(&default_meta);
+        }
+        owners.remove(&0);
+
+        let mut place = Self::init(old.cheddar, old.admin, old.treasury, old.ends, board);
+        place.account_indices = old.account_indices;
+        place.accounts = old.accounts;
+        place.num_accounts = old.num_accounts;
+        place.last_reward_timestamp = old.last_reward_timestamp;
+        // the old state doesn't split the bought Milk by the payment
+        place.bought_milk = old.bought_balances[Berry::Milk as usize];
+        place.used_milk = old.used_milk;
+        place.farmed_cheddar = old.farmed_cheddar;
+        place.is_active = old.is_active;
+        place.mint_funded = old.mint_funded;
+        place.reward_rate = old.reward_rate;
+        place.milk_price = old.milk_price;
+        place.blacklist = old.blacklist;
+        place.starts = old.starts;
+        place.num_pixel_owners = owners.len() as u32;
+        for (account_index, num_pixels) in owners {
+            place
+                .pixels_leaderboard
+                .update(account_index, Balance::from(num_pixels));
+        }
+
+        let price = place.milk_price * MILK_CHEDAR_FACTOR;
+        place.milk_cheddar_price_bounds = (price / 10, price * 10);
+        let admin = place.admin.clone();
+        place.internal_set_milk_cheddar_price(price, admin);
+        place
+    }
+}
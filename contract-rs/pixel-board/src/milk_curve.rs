@@ -0,0 +1,142 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+
+use crate::*;
+
+/// Maximum `growth` (in basis points) of the exponential curve.
+pub const MAX_CURVE_GROWTH: u32 = 10_000;
+
+/// Fixed point scale used to compute the exponential curve prices.
+const ONE: Balance = 1_000_000_000;
+
+/// Bonding curve for Milk sold for NEAR. The price depends on the number of
+/// Milk tokens bought so far (`supply`).
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub enum MilkCurve {
+    /// price of the Milk sold after `supply` tokens is `base + slope * supply`
+    Linear { base: U128, slope: U128 },
+    /// price starts at `base` and grows by `growth` basis points after every
+    /// `step` Milk sold
+    Exponential { base: U128, growth: u32, step: U128 },
+}
+
+impl MilkCurve {
+    pub fn assert_valid(&self) {
+        match self {
+            MilkCurve::Linear { base, .. } => assert!(base.0 > 0, "base price must be positive"),
+            MilkCurve::Exponential { base, growth, step } => {
+                assert!(base.0 > 0, "base price must be positive");
+                assert!(step.0 > 0, "step must be positive");
+                assert!(*growth <= MAX_CURVE_GROWTH, "growth is too big");
+            }
+        }
+    }
+
+    /// Returns the amount of Milk which can be bought for `near_amount` when
+    /// `supply` Milk was already bought, and the exact cost of it (sum of
+    /// prices of all bought tokens).
+    pub fn buy(&self, supply: Balance, near_amount: Balance) -> (Balance, Balance) {
+        match self {
+            MilkCurve::Linear { base, slope } => {
+                buy_max(near_amount / base.0, near_amount, |amount| {
+                    linear_cost(base.0, slope.0, supply, amount)
+                })
+            }
+            MilkCurve::Exponential { base, growth, step } => {
+                let total = |n| exponential_cost(base.0, *growth, step.0, n);
+                // the price after a huge supply doesn't fit into u128, nobody can pay it
+                let sold = match total(supply) {
+                    Some(sold) => sold,
+                    None => return (0, 0),
+                };
+                buy_max(near_amount / base.0, near_amount, |amount| {
+                    total(supply.checked_add(amount)?).map(|c| c.saturating_sub(sold))
+                })
+            }
+        }
+    }
+}
+
+/// Binary search of the biggest amount (at most `max_amount`) which `cost`
+/// not more than `near_amount`. `cost` must grow with the amount and return
+/// None when the cost doesn't fit into u128. Returns the amount and its cost.
+fn buy_max(
+    max_amount: Balance,
+    near_amount: Balance,
+    cost: impl Fn(Balance) -> Option<Balance>,
+) -> (Balance, Balance) {
+    let (mut lo, mut hi) = (0, max_amount);
+    while lo < hi {
+        let mid = hi - (hi - lo) / 2;
+        match cost(mid) {
+            Some(c) if c <= near_amount => lo = mid,
+            _ => hi = mid - 1,
+        }
+    }
+    (lo, cost(lo).unwrap_or(0))
+}
+
+/// Sum of prices of `amount` tokens sold after `supply` tokens.
+/// Returns None on overflow.
+fn linear_cost(base: Balance, slope: Balance, supply: Balance, amount: Balance) -> Option<Balance> {
+    if amount == 0 {
+        return Some(0);
+    }
+    // sum of (supply + i) for i in 0..amount
+    let supply_sum = amount
+        .checked_mul(supply)?
+        .checked_add(amount.checked_mul(amount - 1)? / 2)?;
+    amount
+        .checked_mul(base)?
+        .checked_add(slope.checked_mul(supply_sum)?)
+}
+
+/// Sum of prices of the first `n` tokens of the exponential curve. The full
+/// steps are summed as a geometric series: `base * step * (r^steps - 1) / (r - 1)`
+/// where `r = 1 + growth / 10_000`. Returns None on overflow.
+fn exponential_cost(base: Balance, growth: u32, step: Balance, n: Balance) -> Option<Balance> {
+    let steps = n / step;
+    let full_steps = if growth == 0 {
+        base.checked_mul(step)?.checked_mul(steps)?
+    } else {
+        let series =
+            (growth_factor(growth, steps)? - ONE).checked_mul(10_000)? / Balance::from(growth);
+        mul_div(base.checked_mul(step)?, series, ONE)?
+    };
+    full_steps.checked_add((n % step).checked_mul(exponential_price(base, growth, steps)?)?)
+}
+
+/// Price after `steps` growths of `growth` basis points: `base * (1 + growth / 10_000)^steps`.
+/// Returns None on overflow.
+fn exponential_price(base: Balance, growth: u32, steps: Balance) -> Option<Balance> {
+    mul_div(base, growth_factor(growth, steps)?, ONE)
+}
+
+/// `(1 + growth / 10_000)^steps` in `ONE` fixed point, computed by
+/// exponentiation by squaring. Returns None on overflow.
+fn growth_factor(growth: u32, steps: Balance) -> Option<Balance> {
+    let mut factor = ONE;
+    let mut square = ONE * Balance::from(10_000 + growth) / 10_000;
+    let mut exp = steps;
+    while exp > 0 {
+        if exp & 1 == 1 {
+            factor = factor.checked_mul(square)? / ONE;
+        }
+        exp >>= 1;
+        if exp > 0 {
+            square = square.checked_mul(square)? / ONE;
+        }
+    }
+    Some(factor)
+}
+
+/// `a * b / c` without overflowing the intermediate product when `a` is big.
+fn mul_div(a: Balance, b: Balance, c: Balance) -> Option<Balance> {
+    a.checked_mul(b).map(|p| p / c).or_else(|| {
+        (a / c)
+            .checked_mul(b)?
+            .checked_add((a % c).checked_mul(b)? / c)
+    })
+}
@@ -8,35 +8,85 @@ pub trait Minter {
     fn ft_mint(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>);
 }
 
+#[ext_contract(ext_ft)]
+pub trait FungibleToken {
+    fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>);
+}
+
 #[near_bindgen]
 impl Place {
-    pub fn mint_callback(&mut self, receiver: AccountId, amount: U128) {
+    /// `receiver` is the account which withdrew the Cheddar (not necessarily
+    /// the mint receiver). `funded` is true if the mint paid for the storage
+    /// in the Cheddar token. It's not set by the callbacks scheduled before the
+    /// upgrade, which didn't track the pending withdrawal.
+    /// The account is not touched: the callback only updates the withdrawal
+    /// fields, so it fits into the callback gas.
+    pub fn mint_callback(&mut self, receiver: AccountId, amount: U128, funded: Option<bool>) {
         assert_self();
         let amount: Balance = amount.into();
+        let mut a = self
+            .get_internal_account_by_id(&receiver)
+            .expect("account not found");
+        if funded.is_some() {
+            a.pending_withdrawal -= amount;
+        }
 
         // Get the unused amount from the `ft_on_transfer` call result.
         match env::promise_result(0) {
             PromiseResult::NotReady => unreachable!(),
             PromiseResult::Successful(_) => {
                 log!("cheddar withdrew successfully {}", amount);
+                a.stats.withdrawn_cheddar += amount;
+                self.withdrawn_cheddar += amount;
                 // check if we can remove the account from the state
-                if let Some(a) = self.get_internal_account_by_id(&receiver) {
-                    if a.is_empty() {
-                        self.accounts.remove(&a.account_index);
-                    }
+                if a.is_empty() {
+                    self.delete_account(&a);
+                    return;
                 }
             }
             PromiseResult::Failed => {
-                let mut a = self.get_mut_account(&receiver);
-                a.balances[Berry::Cheddar as usize] = amount;
-                self.save_account(a);
+                // Cheddar farmed or deposited in the meantime stays on the account
+                a.balances[Berry::Cheddar as usize] += amount;
+                self.refunded_cheddar += amount;
+                if funded == Some(true) {
+                    a.mint_funded = false;
+                    self.mint_funded -= 1;
+                }
                 env::log(format!("Refund {} to {}", amount, receiver,).as_bytes());
             }
         };
+        self.save_account(a);
+    }
+
+    /// Restores the deposited Cheddar when `ft_transfer` failed.
+    pub fn transfer_callback(&mut self, receiver: AccountId, amount: U128) {
+        assert_self();
+        let amount: Balance = amount.into();
+        let mut a = self
+            .get_internal_account_by_id(&receiver)
+            .expect("account not found");
+        a.pending_withdrawal -= amount;
+
+        match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(_) => {
+                log!("cheddar deposit withdrew successfully {}", amount);
+                if a.is_empty() {
+                    self.delete_account(&a);
+                    return;
+                }
+            }
+            PromiseResult::Failed => {
+                a.deposited_cheddar += amount;
+                env::log(format!("Refund {} deposit to {}", amount, receiver,).as_bytes());
+            }
+        };
+        self.save_account(a);
     }
 }
 
 #[ext_contract(ext_self)]
 trait MinterResolver {
-    fn mint_callback(&mut self, receiver: AccountId, amount: U128) -> U128;
+    fn mint_callback(&mut self, receiver: AccountId, amount: U128, funded: Option<bool>) -> U128;
+    fn transfer_callback(&mut self, receiver: AccountId, amount: U128);
 }
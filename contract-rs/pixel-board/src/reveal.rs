@@ -0,0 +1,146 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{Base64VecU8, ValidAccountId, U128};
+use near_sdk::serde::Serialize;
+
+use crate::*;
+
+/// Hidden draw: only the hash of the pixels is published with
+/// `commit_draw`, the pixels are applied with `reveal_draw`.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct DrawCommitment {
+    /// see `commitment_hash`
+    pub hash: Vec<u8>,
+    /// Milk reserved for the draw
+    pub milk: Balance,
+    /// in nanoseconds
+    pub committed_at: u64,
+    pub block: u64,
+}
+
+impl DrawCommitment {
+    /// Time (in nanoseconds) after which the draw can't be revealed.
+    fn expires_at(&self, reveal_window: u64) -> u64 {
+        self.committed_at
+            .saturating_add(reveal_window.saturating_mul(FROM_NANO))
+    }
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct HumanDrawCommitment {
+    pub hash: Base64VecU8,
+    pub milk: U128,
+    /// unix timestamp (in seconds) after which the draw can't be revealed
+    pub expires_at: u64,
+}
+
+/// sha256 of `x, y, color (3 bytes, big endian), price (4 bytes, big endian,
+/// 0 when not set)` of every pixel followed by the salt bytes.
+pub fn commitment_hash(pixels: &[SetPixelRequest], salt: &str) -> Vec<u8> {
+    let mut data = Vec::with_capacity(pixels.len() * 9 + salt.len());
+    for p in pixels {
+        data.push(p.x);
+        data.push(p.y);
+        data.extend_from_slice(&p.color.to_be_bytes()[1..]);
+        data.extend_from_slice(&p.price.unwrap_or(0).to_be_bytes());
+    }
+    data.extend_from_slice(salt.as_bytes());
+    env::sha256(&data)
+}
+
+#[near_bindgen]
+impl Place {
+    /// Publishes the hash of the pixels to draw (see `commitment_hash`) and
+    /// reserves `milk` for the draw. The pixels must be revealed in a later
+    /// block, within `reveal_window` seconds.
+    pub fn commit_draw(&mut self, hash: Base64VecU8, milk: U128) {
+        self.assert_active();
+        let account_id = env::predecessor_account_id();
+        assert!(!self.blacklist.contains(&account_id), "Account blacklisted");
+        assert_eq!(hash.0.len(), 32, "Invalid hash");
+
+        let mut account = self.get_mut_account(&account_id);
+        let now = env::block_timestamp();
+        if let Some(c) = account.commitment.take() {
+            assert!(
+                now > c.expires_at(self.reveal_window),
+                "Previous draw is not revealed yet"
+            );
+            account.balances[Berry::Milk as usize] += c.milk;
+        }
+        assert!(
+            account.balances[Berry::Milk as usize] >= milk.0,
+            "Not enough balance to reserve"
+        );
+        account.balances[Berry::Milk as usize] -= milk.0;
+        account.commitment = Some(DrawCommitment {
+            hash: hash.0,
+            milk: milk.0,
+            committed_at: now,
+            block: env::block_index(),
+        });
+        self.save_account(account);
+    }
+
+    /// Draws the committed pixels. The reserved Milk is returned to the
+    /// balance before paying for the pixels.
+    pub fn reveal_draw(&mut self, pixels: Vec<SetPixelRequest>, salt: String) {
+        self.assert_active();
+        let account_id = env::predecessor_account_id();
+        assert!(!self.blacklist.contains(&account_id), "Account blacklisted");
+
+        let mut account = self.get_mut_account(&account_id);
+        let c = account.commitment.take().expect("No draw commitment");
+        assert!(
+            env::block_index() > c.block,
+            "Draw must be revealed in a later block"
+        );
+        assert!(
+            env::block_timestamp() <= c.expires_at(self.reveal_window),
+            "Commitment expired"
+        );
+        assert!(
+            commitment_hash(&pixels, &salt) == c.hash,
+            "Pixels don't match the commitment"
+        );
+        account.balances[Berry::Milk as usize] += c.milk;
+        self.save_account(account);
+
+        if !pixels.is_empty() {
+            self.internal_draw(&account_id, &pixels);
+        }
+    }
+
+    /// Returns the Milk reserved by an expired commitment.
+    pub fn refund_draw_commitment(&mut self) -> U128 {
+        let account_id = env::predecessor_account_id();
+        let mut account = self
+            .get_internal_account_by_id(&account_id)
+            .expect("account not found");
+        let c = account.commitment.take().expect("No draw commitment");
+        assert!(
+            env::block_timestamp() > c.expires_at(self.reveal_window),
+            "Commitment is not expired yet"
+        );
+        self.touch(&mut account);
+        account.balances[Berry::Milk as usize] += c.milk;
+        self.save_account(account);
+        c.milk.into()
+    }
+
+    pub fn get_draw_commitment(&self, account_id: ValidAccountId) -> Option<HumanDrawCommitment> {
+        self.get_internal_account_by_id(account_id.as_ref())
+            .and_then(|a| a.commitment)
+            .map(|c| HumanDrawCommitment {
+                expires_at: c.expires_at(self.reveal_window) / FROM_NANO,
+                hash: c.hash.into(),
+                milk: c.milk.into(),
+            })
+    }
+
+    /// Sets the time (in seconds) to reveal a committed draw.
+    pub fn set_reveal_window(&mut self, reveal_window: u64) {
+        self.only_admin();
+        self.reveal_window = reveal_window;
+    }
+}
@@ -0,0 +1,227 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{Base64VecU8, U128};
+use near_sdk::serde::Serialize;
+
+use crate::*;
+
+/// Final state of a closed season.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Season {
+    /// timestamps in nanoseconds
+    pub starts: u64,
+    pub ends: u64,
+    pub closed_at: u64,
+    /// sha256 of the borsh serialized board lines
+    pub board_hash: Vec<u8>,
+    /// accounts with the most pixels at the end of the season
+    pub rankings: Vec<(AccountId, u32)>,
+    /// Cheddar split between the ranked accounts
+    pub prize: Balance,
+}
+
+/// Progress of `Place::settle_season`. Accounts are processed in pages, in
+/// two passes: the first one settles the farming and the taxes, the second
+/// one pays the prizes from the final pixel counts and resets the accounts.
+#[derive(BorshDeserialize, BorshSerialize, Default)]
+pub struct SeasonSettlement {
+    /// index of the next account to process
+    pub next_index: AccountIndex,
+    /// true during the second pass
+    pub paying: bool,
+    /// accounts with the most pixels, collected in the first pass
+    pub ranked: Leaderboard,
+    /// (account, number of pixels) of the ranked accounts paid so far
+    pub rankings: Vec<(AccountId, u32)>,
+    /// season prize paid so far
+    pub prize_paid: Balance,
+}
+
+impl SeasonSettlement {
+    fn is_done(&self, num_accounts: AccountIndex) -> bool {
+        self.paying && self.next_index >= num_accounts
+    }
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SeasonInfo {
+    pub season_id: u32,
+    /// timestamps in seconds
+    pub starts: u64,
+    pub ends: u64,
+    pub closed_at: u64,
+    pub board_hash: Base64VecU8,
+    /// (account, number of pixels)
+    pub rankings: Vec<(AccountId, u32)>,
+    pub prize: U128,
+}
+
+impl Place {
+    fn season_line_key(season_id: u32, line: u32) -> u64 {
+        u64::from(season_id) * u64::from(BOARD_HEIGHT) + u64::from(line)
+    }
+
+    /// Adds Cheddar transferred to the contract to the prize of the current
+    /// season, split between the accounts with the most pixels at the end of
+    /// the season, proportionally to their pixels.
+    pub(crate) fn fund_season_prize(&mut self, amount: Balance) {
+        assert!(self.season_settlement.is_none(), "Season is being settled");
+        self.season_prize += amount;
+        self.prize_funds += amount;
+        log!("Season prize funded with {} Cheddar", amount);
+    }
+
+    /// Credits the season prize share and the unclaimed team bonus of the
+    /// account and resets its pixels.
+    fn pay_season_prizes(&mut self, s: &mut SeasonSettlement, a: &mut Account) {
+        let ranked_pixels: Balance = s.ranked.0.iter().map(|(_, n)| *n).sum();
+        if let Some((_, n)) = s.ranked.0.iter().find(|(i, _)| *i == a.account_index) {
+            let prize = self.season_prize * n / ranked_pixels;
+            self.pay_prize(a, prize);
+            s.prize_paid += prize;
+            s.rankings.push((a.account_id.clone(), a.num_pixels));
+        }
+        if a.team.is_some() && a.team_bonus_claimed != Some(self.season) {
+            let bonus = &mut self.team_bonus;
+            let b = (bonus.pool * Balance::from(a.num_pixels))
+                .checked_div(Balance::from(bonus.territory))
+                .unwrap_or(0)
+                .min(bonus.pool - bonus.paid);
+            bonus.paid += b;
+            a.team_bonus_claimed = Some(self.season);
+            self.pay_prize(a, b);
+        }
+        a.num_pixels = 0;
+        a.declared_value = 0;
+        a.accrued_tax = 0;
+    }
+}
+
+#[near_bindgen]
+impl Place {
+    /// Processes up to `limit` account indices of the finished season, must be
+    /// called until it returns true before `close_season`. The first pass settles
+    /// the farming and the taxes of the pixel owners (foreclosed pixels don't
+    /// count), the second one credits the season prize to the ranked accounts,
+    /// pays the unclaimed team bonuses and resets the owned pixels.
+    pub fn settle_season(&mut self, limit: u32) -> bool {
+        self.only_admin();
+        assert!(env::block_timestamp() > self.ends, "Season is not over yet");
+        let mut s = self.season_settlement.take().unwrap_or_default();
+        let mut left = limit;
+        while left > 0 && !s.is_done(self.num_accounts) {
+            let to_index = std::cmp::min(s.next_index.saturating_add(left), self.num_accounts);
+            for i in s.next_index.max(1)..to_index {
+                let mut a = match self.get_internal_account_by_index(i) {
+                    Some(a) if a.num_pixels > 0 => a,
+                    _ => continue,
+                };
+                if s.paying {
+                    self.pay_season_prizes(&mut s, &mut a);
+                } else {
+                    self.touch(&mut a);
+                    s.ranked.update(i, Balance::from(a.num_pixels));
+                }
+                self.save_account(a);
+            }
+            left -= to_index - s.next_index;
+            s.next_index = to_index;
+            if !s.paying && s.next_index >= self.num_accounts {
+                s.paying = true;
+                s.next_index = 0;
+                // the taxes are settled, so the territory doesn't change anymore
+                if self.team_bonus.snapshot_season != Some(self.season) {
+                    self.team_bonus.territory = self.teams.iter().map(|t| t.territory).sum();
+                    self.team_bonus.snapshot_season = Some(self.season);
+                }
+            }
+        }
+        let done = s.is_done(self.num_accounts);
+        self.season_settlement = Some(s);
+        done
+    }
+
+    /// Closes the settled season (see `settle_season`): archives the board
+    /// and the rankings and opens the next season with a fresh board. The
+    /// unpaid season prize and team bonus go to the next season. `starts`
+    /// and `ends` are unix timestamps (in seconds) of the next season.
+    pub fn close_season(&mut self, starts: u64, ends: u64) -> u32 {
+        self.only_admin();
+        assert!(starts < ends, "start must be before end");
+        let settlement = self
+            .season_settlement
+            .take()
+            .filter(|s| s.is_done(self.num_accounts))
+            .expect("Season is not settled yet");
+
+        let season_id = self.season;
+        let mut board = Vec::new();
+        for i in 0..u32::from(BOARD_HEIGHT) {
+            let line = self.board.get_line(i);
+            board.extend(line.try_to_vec().unwrap());
+            self.season_lines
+                .insert(&Self::season_line_key(season_id, i), &line.compress());
+        }
+        let mut rankings = settlement.rankings;
+        rankings.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        self.seasons.insert(
+            &season_id,
+            &Season {
+                starts: self.starts,
+                ends: self.ends,
+                closed_at: env::block_timestamp(),
+                board_hash: env::sha256(&board),
+                rankings,
+                prize: settlement.prize_paid,
+            },
+        );
+        log!(
+            "Season {} closed, prize {} Cheddar, team bonus {} Cheddar",
+            season_id,
+            settlement.prize_paid,
+            self.team_bonus.paid
+        );
+
+        // the accounts were reset by `settle_season`
+        self.reset_board_lines();
+        self.season_prize -= settlement.prize_paid;
+        self.team_bonus = TeamBonus {
+            pool: self.team_bonus.pool - self.team_bonus.paid,
+            ..Default::default()
+        };
+        self.season += 1;
+        self.starts = starts * FROM_NANO;
+        self.ends = ends * FROM_NANO;
+        self.season
+    }
+
+    pub fn get_current_season(&self) -> u32 {
+        self.season
+    }
+
+    pub fn get_season(&self, season_id: u32) -> Option<SeasonInfo> {
+        self.seasons.get(&season_id).map(|s| SeasonInfo {
+            season_id,
+            starts: s.starts / FROM_NANO,
+            ends: s.ends / FROM_NANO,
+            closed_at: s.closed_at / FROM_NANO,
+            board_hash: s.board_hash.into(),
+            rankings: s.rankings,
+            prize: s.prize.into(),
+        })
+    }
+
+    /// Returns run-length encoded lines of an archived season board, see
+    /// `PixelLine::compress`.
+    pub fn get_season_lines(&self, season_id: u32, lines: Vec<u32>) -> Vec<Base64VecU8> {
+        lines
+            .into_iter()
+            .map(|i| {
+                self.season_lines
+                    .get(&Self::season_line_key(season_id, i))
+                    .expect("Season line not found")
+                    .into()
+            })
+            .collect()
+    }
+}
@@ -1,35 +1,157 @@
-use std::convert::TryInto;
-
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
 use near_sdk::json_types::U128;
 use near_sdk::serde::{Deserialize, Serialize};
 
 use crate::*;
 
+/// Number of daily buckets kept in `Place::daily_stats`. When full, a new day
+/// overwrites the oldest bucket.
+pub const MAX_DAILY_STATS: u64 = 366;
+pub(crate) const DAY: u64 = 24 * 60 * 60 * FROM_NANO;
+
 #[derive(Serialize, Deserialize)]
 #[serde(crate = "near_sdk::serde")]
 pub struct Stats {
-    bought_milk: u64,
-    used_milk: u64,
-    num_accounts: u32,
-    reward_rate: U128,
-    milk_price: U128,
-    cheddar_milk_price: U128,
-    starts_at: u64,
-    ends_at: u64,
+    pub bought_milk: U128,
+    pub bought_milk_with_near: U128,
+    pub bought_milk_with_cheddar: U128,
+    /// payment token stats: (token, stats)
+    pub payment_tokens: Vec<(AccountId, PaymentToken)>,
+    pub used_milk: U128,
+    pub farmed_cheddar: U128,
+    /// farmed Cheddar minted to the accounts
+    pub withdrawn_cheddar: U128,
+    /// Cheddar of the failed mints returned to the accounts
+    pub refunded_cheddar: U128,
+    /// Cheddar deposited with `ft_transfer_call`
+    pub deposited_cheddar: U128,
+    /// season prizes and team bonuses paid to the accounts
+    pub paid_prizes: U128,
+    /// NEAR paid for Milk
+    pub near_collected: U128,
+    /// NEAR transferred to the treasury
+    pub treasury_withdrawn: U128,
+    /// number of accounts with storage funded in the Cheddar token
+    pub mint_funded: u32,
+    pub num_accounts: u32,
+    pub reward_rate: U128,
+    pub milk_price: U128,
+    pub cheddar_milk_price: U128,
+    pub starts_at: u64,
+    pub ends_at: u64,
 }
 
 #[near_bindgen]
 impl Place {
     pub fn stats(&self) -> Stats {
         Stats {
-            bought_milk: self.bought_balances[0].try_into().unwrap(),
-            used_milk: self.used_milk.try_into().unwrap(),
-            num_accounts: self.num_accounts,
+            bought_milk: self.bought_milk.into(),
+            bought_milk_with_near: self.milk_bought_with_near.into(),
+            bought_milk_with_cheddar: self.milk_bought_with_cheddar.into(),
+            payment_tokens: self.payment_tokens.to_vec(),
+            used_milk: self.used_milk.into(),
+            farmed_cheddar: self.farmed_cheddar.into(),
+            withdrawn_cheddar: self.withdrawn_cheddar.into(),
+            refunded_cheddar: self.refunded_cheddar.into(),
+            deposited_cheddar: self.deposited_cheddar.into(),
+            paid_prizes: self.paid_prizes.into(),
+            near_collected: self.near_collected.into(),
+            treasury_withdrawn: self.treasury_withdrawn.into(),
+            mint_funded: self.mint_funded,
+            num_accounts: self.num_accounts - self.free_account_indices.len() as u32,
             reward_rate: self.reward_rate.into(),
             milk_price: self.milk_price.into(),
-            cheddar_milk_price: (self.milk_price * MILK_CHEDAR_FACTOR).into(),
+            cheddar_milk_price: self.milk_cheddar_price.into(),
             starts_at: self.starts,
             ends_at: self.ends,
         }
     }
+
+    /// Returns up to `limit` daily buckets (oldest first) starting from
+    /// `from_day` (days since the unix epoch). Only the last
+    /// `MAX_DAILY_STATS` active days are kept.
+    pub fn get_daily_stats(&self, from_day: u64, limit: u64) -> Vec<DailyStats> {
+        let len = self.daily_stats.len();
+        // the oldest bucket is the next one to be overwritten
+        let start = if len < MAX_DAILY_STATS {
+            0
+        } else {
+            self.num_daily_stats % MAX_DAILY_STATS
+        };
+        (0..len)
+            .map(|i| self.daily_stats.get((start + i) % len).unwrap())
+            .filter(|s| s.day >= from_day)
+            .take(limit as usize)
+            .collect()
+    }
+}
+
+/// Game activity during one day. Only days with any activity have a bucket.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DailyStats {
+    /// days since the unix epoch
+    pub day: u64,
+    pub pixels_drawn: u64,
+    pub milk_bought: U128,
+    pub milk_used: U128,
+    pub farmed_cheddar: U128,
+    /// Cheddar requested with `withdraw_crop`
+    pub withdrawn_cheddar: U128,
+    pub near_received: U128,
+    /// number of accounts which made any action during the day
+    pub active_accounts: u32,
+}
+
+impl DailyStats {
+    fn new(day: u64) -> Self {
+        Self {
+            day,
+            pixels_drawn: 0,
+            milk_bought: 0.into(),
+            milk_used: 0.into(),
+            farmed_cheddar: 0.into(),
+            withdrawn_cheddar: 0.into(),
+            near_received: 0.into(),
+            active_accounts: 0,
+        }
+    }
+}
+
+impl Place {
+    /// Updates the bucket of the current day, starting a new one if needed.
+    pub(crate) fn record_daily(&mut self, f: impl FnOnce(&mut DailyStats)) {
+        let day = env::block_timestamp() / DAY;
+        let last = self
+            .num_daily_stats
+            .checked_sub(1)
+            .map(|i| i % MAX_DAILY_STATS);
+        let (index, mut stats) = match last.and_then(|i| self.daily_stats.get(i).map(|s| (i, s))) {
+            Some((i, s)) if s.day == day => (i, s),
+            _ => {
+                self.num_daily_stats += 1;
+                (
+                    (self.num_daily_stats - 1) % MAX_DAILY_STATS,
+                    DailyStats::new(day),
+                )
+            }
+        };
+        f(&mut stats);
+        if index < self.daily_stats.len() {
+            self.daily_stats.replace(index, &stats);
+        } else {
+            self.daily_stats.push(&stats);
+        }
+    }
+
+    /// Updates the account last activity and counts the account as active
+    /// on its first action of the day.
+    pub(crate) fn record_activity(&mut self, account: &mut Account) {
+        let now = env::block_timestamp();
+        let last = account.stats.last_activity;
+        if last == 0 || last / DAY < now / DAY {
+            self.record_daily(|s| s.active_accounts += 1);
+        }
+        account.stats.last_activity = now;
+    }
 }
@@ -0,0 +1,165 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+
+use crate::*;
+
+pub const MAX_TEAMS: usize = 16;
+
+pub type TeamId = u8;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Team {
+    pub name: String,
+    pub members: u32,
+    /// number of pixels owned by the team members
+    pub territory: u32,
+}
+
+/// Cheddar split between the team members at the end of the season,
+/// proportionally to the owned pixels. The pool is funded with Cheddar
+/// transferred to the contract (see `TransferAction::FundTeamBonus`).
+#[derive(BorshDeserialize, BorshSerialize, Default)]
+pub struct TeamBonus {
+    pub pool: Balance,
+    /// Cheddar already paid from the pool
+    pub paid: Balance,
+    /// season the `territory` snapshot was taken for
+    pub snapshot_season: Option<u32>,
+    /// total territory of all teams at the end of the season
+    pub territory: u32,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TeamScore {
+    pub team_id: TeamId,
+    pub name: String,
+    pub members: u32,
+    pub territory: u32,
+    /// team share of the bonus pool
+    pub bonus: U128,
+}
+
+impl Place {
+    /// Total territory used to split the bonus pool: the snapshot taken at
+    /// the first claim, or the live value before it.
+    fn team_bonus_territory(&self) -> u32 {
+        if self.team_bonus.snapshot_season == Some(self.season) {
+            self.team_bonus.territory
+        } else {
+            self.teams.iter().map(|t| t.territory).sum()
+        }
+    }
+
+    /// Adds Cheddar transferred to the contract to the team bonus pool.
+    pub(crate) fn fund_team_bonus(&mut self, amount: Balance) {
+        assert!(
+            self.team_bonus.snapshot_season != Some(self.season),
+            "Team bonus is already being claimed"
+        );
+        self.team_bonus.pool += amount;
+        self.prize_funds += amount;
+        log!("Team bonus pool funded with {} Cheddar", amount);
+    }
+
+    /// Credits a season prize or a team bonus to the deposited Cheddar of the
+    /// account. Prizes are paid from the Cheddar received to fund them.
+    pub(crate) fn pay_prize(&mut self, account: &mut Account, amount: Balance) {
+        assert!(amount <= self.prize_funds, "Prize is not funded");
+        self.prize_funds -= amount;
+        self.paid_prizes += amount;
+        account.deposited_cheddar += amount;
+        account.stats.prizes += amount;
+    }
+}
+
+#[near_bindgen]
+impl Place {
+    /// Joins the predecessor to the team. Pixels owned by the account move to
+    /// the new team territory.
+    pub fn join_team(&mut self, team_id: TeamId) {
+        self.assert_active();
+        let account_id = env::predecessor_account_id();
+        assert!(!self.blacklist.contains(&account_id), "Account blacklisted");
+        assert!((team_id as usize) < self.teams.len(), "Team not found");
+
+        let mut account = self.get_mut_account(&account_id);
+        if let Some(old) = account.team {
+            let old = &mut self.teams[old as usize];
+            old.members -= 1;
+            old.territory -= account.num_pixels;
+        }
+        let team = &mut self.teams[team_id as usize];
+        team.members += 1;
+        team.territory += account.num_pixels;
+        account.team = Some(team_id);
+        log!("{} joined team {}", account_id, team.name);
+        self.save_account(account);
+    }
+
+    /// Pays the predecessor's share of the team bonus pool to the deposited
+    /// Cheddar balance. Available once the season is over.
+    pub fn claim_team_bonus(&mut self) -> U128 {
+        assert!(env::block_timestamp() > self.ends, "Game is not over yet");
+        let account_id = env::predecessor_account_id();
+        assert!(!self.blacklist.contains(&account_id), "Account blacklisted");
+        let mut account = self
+            .get_internal_account_by_id(&account_id)
+            .expect("account not found");
+        assert!(account.team.is_some(), "Account is not in a team");
+        assert!(
+            account.team_bonus_claimed != Some(self.season),
+            "Team bonus already claimed"
+        );
+        self.touch(&mut account);
+        if self.team_bonus.snapshot_season != Some(self.season) {
+            // pixels can't be drawn anymore, so the territory only decreases
+            self.team_bonus.territory = self.team_bonus_territory();
+            self.team_bonus.snapshot_season = Some(self.season);
+        }
+        let bonus = (self.team_bonus.pool * Balance::from(account.num_pixels))
+            .checked_div(Balance::from(self.team_bonus.territory))
+            .unwrap_or(0);
+        assert!(
+            self.team_bonus.paid + bonus <= self.team_bonus.pool,
+            "Team bonus pool is exhausted"
+        );
+        self.team_bonus.paid += bonus;
+        account.team_bonus_claimed = Some(self.season);
+        self.pay_prize(&mut account, bonus);
+        log!("{} claimed {} Cheddar team bonus", account_id, bonus);
+        self.save_account(account);
+        bonus.into()
+    }
+
+    pub fn get_team_scores(&self) -> Vec<TeamScore> {
+        let territory = Balance::from(self.team_bonus_territory());
+        self.teams
+            .iter()
+            .enumerate()
+            .map(|(i, t)| TeamScore {
+                team_id: i as TeamId,
+                name: t.name.clone(),
+                members: t.members,
+                territory: t.territory,
+                bonus: (self.team_bonus.pool * Balance::from(t.territory))
+                    .checked_div(territory)
+                    .unwrap_or(0)
+                    .into(),
+            })
+            .collect()
+    }
+
+    pub fn add_team(&mut self, name: String) -> TeamId {
+        self.only_admin();
+        assert!(self.teams.len() < MAX_TEAMS, "Too many teams");
+        self.teams.push(Team {
+            name,
+            members: 0,
+            territory: 0,
+        });
+        (self.teams.len() - 1) as TeamId
+    }
+}
@@ -0,0 +1,289 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde::Serialize;
+use std::collections::{HashMap, HashSet};
+
+use crate::*;
+
+pub const MAX_ACTIVE_TEMPLATES: usize = 10;
+pub const MAX_TEMPLATE_AREA: u32 = 1024;
+
+pub type TemplateId = u32;
+
+/// Target image over a board region. Painting a pixel with the template
+/// color (when it didn't match before) counts as a contribution, which is
+/// taken back when the pixel stops matching. The reward is split between the
+/// contributors once the template is completed or its deadline passes.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Template {
+    pub rect: Rect,
+    /// target colors of the rect pixels, row by row
+    pub colors: Vec<u32>,
+    /// Cheddar split between the contributors
+    pub reward: Balance,
+    /// in nanoseconds
+    pub deadline: u64,
+    /// number of pixels matching the template
+    pub matching: u32,
+    /// number of matching pixels credited to a contributor
+    pub contributions: u32,
+    /// time (in nanoseconds) when all pixels matched, 0 if not completed
+    pub completed_at: u64,
+    /// true when the `template_pixel_contributors` entries of the finished
+    /// template were removed
+    pub contributors_cleared: bool,
+}
+
+/// Color change of a board pixel, see `Place::track_templates`.
+pub(crate) struct PixelChange {
+    pub x: u8,
+    pub y: u8,
+    pub old_color: u32,
+    pub color: u32,
+    /// account credited when the pixel starts matching a template
+    pub painter: Option<AccountId>,
+}
+
+impl Template {
+    fn index(&self, x: u8, y: u8) -> u32 {
+        u32::from(y - self.rect.y) * u32::from(self.rect.width) + u32::from(x - self.rect.x)
+    }
+
+    fn target(&self, x: u8, y: u8) -> u32 {
+        self.colors[self.index(x, y) as usize]
+    }
+
+    fn is_finished(&self, now: u64) -> bool {
+        self.completed_at > 0 || now > self.deadline
+    }
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TemplateInfo {
+    pub template_id: TemplateId,
+    pub rect: Rect,
+    pub colors: Vec<u32>,
+    pub reward: U128,
+    /// timestamps in seconds
+    pub deadline: u64,
+    pub matching: u32,
+    pub contributions: u32,
+    pub completed_at: u64,
+}
+
+impl Place {
+    /// Updates active templates after the pixel `changes`. A pixel which
+    /// starts matching is credited to its painter, the contribution is taken
+    /// back when the pixel stops matching.
+    pub(crate) fn track_templates(&mut self, changes: &[PixelChange]) {
+        let now = env::block_timestamp();
+        let active = self.active_templates.clone();
+        for (id, rect) in active {
+            if !changes.iter().any(|c| rect.contains(c.x, c.y)) {
+                continue;
+            }
+            let mut t = self.templates.get(u64::from(id)).unwrap();
+            if t.is_finished(now) {
+                self.active_templates.retain(|(i, _)| *i != id);
+                continue;
+            }
+            for c in changes.iter().filter(|c| rect.contains(c.x, c.y)) {
+                let i = t.index(c.x, c.y);
+                let target = t.colors[i as usize];
+                match (c.old_color == target, c.color == target) {
+                    (true, false) => {
+                        t.matching -= 1;
+                        if let Some(contributor) = self.template_pixel_contributors.remove(&(id, i))
+                        {
+                            let key = (id, contributor);
+                            let n = self.template_contributions.get(&key).unwrap() - 1;
+                            if n == 0 {
+                                self.template_contributions.remove(&key);
+                            } else {
+                                self.template_contributions.insert(&key, &n);
+                            }
+                            t.contributions -= 1;
+                        }
+                    }
+                    (false, true) => {
+                        t.matching += 1;
+                        if let Some(painter) = &c.painter {
+                            self.template_pixel_contributors.insert(&(id, i), painter);
+                            let key = (id, painter.clone());
+                            let n = self.template_contributions.get(&key).unwrap_or(0) + 1;
+                            self.template_contributions.insert(&key, &n);
+                            t.contributions += 1;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            if t.matching == rect.area() {
+                t.completed_at = now;
+                self.active_templates.retain(|(i, _)| *i != id);
+                log!("Template {} completed", id);
+            }
+            self.templates.replace(u64::from(id), &t);
+        }
+    }
+
+    /// Changes of the active templates pixels when the board is cleared.
+    pub(crate) fn clear_templates_changes(&self) -> Vec<PixelChange> {
+        let mut coords = HashSet::new();
+        for (_, rect) in &self.active_templates {
+            for y in rect.y..rect.y + rect.height {
+                coords.extend((rect.x..rect.x + rect.width).map(|x| (x, y)));
+            }
+        }
+        let mut lines = HashMap::new();
+        coords
+            .into_iter()
+            .map(|(x, y)| {
+                let line = lines
+                    .entry(y)
+                    .or_insert_with(|| self.board.get_line(u32::from(y)));
+                PixelChange {
+                    x,
+                    y,
+                    old_color: line.0[x as usize].color,
+                    color: Pixel::default().color,
+                    painter: None,
+                }
+            })
+            .collect()
+    }
+}
+
+#[near_bindgen]
+impl Place {
+    /// Posts a template over `rect` with target `colors` (row by row) and a
+    /// Cheddar `reward`. `deadline` is a unix timestamp in seconds.
+    pub fn post_template(
+        &mut self,
+        rect: Rect,
+        colors: Vec<u32>,
+        reward: U128,
+        deadline: u64,
+    ) -> TemplateId {
+        self.only_admin();
+        rect.assert_valid();
+        assert!(rect.area() <= MAX_TEMPLATE_AREA, "Template is too large");
+        assert_eq!(
+            colors.len(),
+            rect.area() as usize,
+            "Number of colors doesn't match the rect area"
+        );
+        assert!(
+            colors.iter().all(|c| *c <= 0xffffff),
+            "Color is out of bounds"
+        );
+        let deadline = deadline * FROM_NANO;
+        assert!(
+            deadline > env::block_timestamp(),
+            "Deadline must be in the future"
+        );
+        let now = env::block_timestamp();
+        let templates = &self.templates;
+        self.active_templates
+            .retain(|(i, _)| !templates.get(u64::from(*i)).unwrap().is_finished(now));
+        assert!(
+            self.active_templates.len() < MAX_ACTIVE_TEMPLATES,
+            "Too many active templates"
+        );
+
+        let mut t = Template {
+            rect,
+            colors,
+            reward: reward.0,
+            deadline,
+            matching: 0,
+            contributions: 0,
+            completed_at: 0,
+            contributors_cleared: false,
+        };
+        for y in rect.y..rect.y + rect.height {
+            let line = self.board.get_line(u32::from(y));
+            for x in rect.x..rect.x + rect.width {
+                if line.0[x as usize].color == t.target(x, y) {
+                    t.matching += 1;
+                }
+            }
+        }
+        let id = self.templates.len() as TemplateId;
+        self.templates.push(&t);
+        self.active_templates.push((id, rect));
+        id
+    }
+
+    /// Credits the predecessor's share of the template reward to the farmed
+    /// Cheddar balance, once the template is completed or its deadline passed.
+    /// The account is registered again if it was deleted since.
+    pub fn claim_template_reward(&mut self, template_id: TemplateId) -> U128 {
+        let account_id = env::predecessor_account_id();
+        assert!(!self.blacklist.contains(&account_id), "Account blacklisted");
+        let mut t = self
+            .templates
+            .get(u64::from(template_id))
+            .expect("Template not found");
+        assert!(
+            t.is_finished(env::block_timestamp()),
+            "Template is not finished yet"
+        );
+        let key = (template_id, account_id.clone());
+        let contributed = self
+            .template_contributions
+            .remove(&key)
+            .expect("No contributions to the template");
+        let reward = t.reward * Balance::from(contributed) / Balance::from(t.contributions);
+        if !t.contributors_cleared {
+            // the pixels of a finished template are not tracked anymore
+            for i in 0..t.rect.area() {
+                self.template_pixel_contributors.remove(&(template_id, i));
+            }
+            t.contributors_cleared = true;
+            self.templates.replace(u64::from(template_id), &t);
+        }
+
+        let mut account = self.get_mut_account(&account_id);
+        account.balances[Berry::Cheddar as usize] += reward;
+        log!(
+            "{} claimed {} Cheddar for template {}",
+            account_id,
+            reward,
+            template_id
+        );
+        self.save_account(account);
+        reward.into()
+    }
+
+    pub fn get_templates(&self, from_index: u64, limit: u64) -> Vec<TemplateInfo> {
+        let to_index = std::cmp::min(from_index.saturating_add(limit), self.templates.len());
+        (from_index..to_index)
+            .map(|i| {
+                let t = self.templates.get(i).unwrap();
+                TemplateInfo {
+                    template_id: i as TemplateId,
+                    rect: t.rect,
+                    colors: t.colors,
+                    reward: t.reward.into(),
+                    deadline: t.deadline / FROM_NANO,
+                    matching: t.matching,
+                    contributions: t.contributions,
+                    completed_at: t.completed_at / FROM_NANO,
+                }
+            })
+            .collect()
+    }
+
+    /// Number of pixels the account painted with the template colors.
+    pub fn get_template_contribution(
+        &self,
+        template_id: TemplateId,
+        account_id: ValidAccountId,
+    ) -> u32 {
+        self.template_contributions
+            .get(&(template_id, account_id.into()))
+            .unwrap_or(0)
+    }
+}
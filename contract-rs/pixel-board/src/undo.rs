@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+
+use crate::*;
+
+/// Larger draws can't be undone.
+pub const MAX_UNDO_PIXELS: usize = 100;
+
+/// Previous state of the pixels of the last draw of an account.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct UndoRecord {
+    /// in nanoseconds
+    pub drawn_at: u64,
+    /// Milk paid for the pixels
+    pub milk: Balance,
+    pub pixels: Vec<UndoPixel>,
+    /// previous owners of the pixels (other than the drawer and the contract)
+    pub owners: Vec<(AccountIndex, AccountId)>,
+}
+
+impl UndoRecord {
+    /// Returns true if the draw can't be undone anymore. A zero `undo_window`
+    /// (in seconds) disables undo.
+    pub fn is_expired(&self, undo_window: u64) -> bool {
+        undo_window == 0
+            || env::block_timestamp()
+                > self
+                    .drawn_at
+                    .saturating_add(undo_window.saturating_mul(FROM_NANO))
+    }
+}
+
+impl Place {
+    /// Removes the undo record of the account once it can't be used, so its
+    /// storage is not kept forever.
+    pub(crate) fn drop_expired_undo_record(&mut self, account_id: &AccountId) {
+        if let Some(record) = self.undo_records.get(account_id) {
+            if record.is_expired(self.undo_window) {
+                self.undo_records.remove(account_id);
+            }
+        }
+    }
+}
+
+#[near_bindgen]
+impl Place {
+    /// Restores the pixels of the predecessor's last draw which were not
+    /// changed since, and refunds `undo_refund` of the Milk paid for them.
+    /// Only available within `undo_window` seconds after the draw.
+    pub fn undo_last_draw(&mut self) -> U128 {
+        self.assert_active();
+        let account_id = env::predecessor_account_id();
+        assert!(!self.blacklist.contains(&account_id), "Account blacklisted");
+
+        let record = self.undo_records.get(&account_id).expect("Nothing to undo");
+        assert!(!record.is_expired(self.undo_window), "Undo window is over");
+        self.undo_records.remove(&account_id);
+        let mut account = self.get_mut_account(&account_id);
+        let num_drawn = record.pixels.len();
+        let mut pixels = record.pixels;
+        // pixels of the accounts deleted since (their index may be reused) go
+        // to the contract account
+        let owners: HashMap<AccountIndex, AccountId> = record
+            .owners
+            .into_iter()
+            .filter(|(i, id)| self.get_account_id_by_index(*i).as_ref() == Some(id))
+            .collect();
+        for p in pixels.iter_mut() {
+            let owner = p.pixel.owner_id;
+            if owner != account.account_index && !owners.contains_key(&owner) {
+                p.pixel.owner_id = 0;
+            }
+        }
+        let restored = self
+            .board
+            .undo_pixels(account.account_index, record.drawn_at, &pixels);
+        let changes: Vec<PixelChange> = restored
+            .iter()
+            .map(|(p, color)| PixelChange {
+                x: p.x,
+                y: p.y,
+                old_color: *color,
+                color: p.pixel.color,
+                // the restored owner is credited again
+                painter: if p.pixel.owner_id == account.account_index {
+                    Some(account_id.clone())
+                } else {
+                    owners.get(&p.pixel.owner_id).cloned()
+                },
+            })
+            .collect();
+        self.track_templates(&changes);
+
+        let mut owners: HashMap<AccountIndex, u32> = HashMap::new();
+        for (p, _) in &restored {
+            *owners.entry(p.pixel.owner_id).or_default() += 1;
+        }
+        let num_restored = restored.len() as u32;
+        let old_num_pixels = account.num_pixels;
+        account.num_pixels =
+            account.num_pixels + owners.remove(&account.account_index).unwrap_or(0) - num_restored;
+        self.update_pixel_counts(&account, old_num_pixels);
+        account.stats.pixels_painted = account
+            .stats
+            .pixels_painted
+            .saturating_sub(u64::from(num_restored));
+
+        let refund = record.milk * Balance::from(num_restored) * Balance::from(self.undo_refund)
+            / (num_drawn as Balance * 10_000);
+        account.balances[Berry::Milk as usize] += refund;
+        account.stats.milk_spent = account.stats.milk_spent.saturating_sub(refund);
+        self.used_milk = self.used_milk.saturating_sub(refund);
+        log!(
+            "Undone {} of {} pixels, refunded {} Milk",
+            num_restored,
+            num_drawn,
+            refund
+        );
+        self.save_account(account);
+
+        for (account_index, n) in owners {
+            let mut a = self.get_internal_account_by_index(account_index).unwrap();
+            self.touch(&mut a);
+            a.num_pixels += n;
+            self.update_pixel_counts(&a, a.num_pixels - n);
+            a.stats.pixels_lost = a.stats.pixels_lost.saturating_sub(u64::from(n));
+            self.save_account(a);
+        }
+        refund.into()
+    }
+
+    /// Sets the undo window (in seconds, zero disables undo) and the part of
+    /// the Milk refunded on undo (in basis points).
+    pub fn set_undo_params(&mut self, undo_window: u64, undo_refund: u32) {
+        self.only_admin();
+        assert!(undo_refund <= 10_000, "refund can't be more than 100%");
+        self.undo_window = undo_window;
+        self.undo_refund = undo_refund;
+    }
+}
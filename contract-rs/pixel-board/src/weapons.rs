@@ -0,0 +1,151 @@
+use near_sdk::json_types::{ValidAccountId, U128};
+use near_sdk::serde::{Deserialize, Serialize};
+
+use crate::*;
+
+/// Maximum number of pixels affected by one weapon.
+pub const MAX_WEAPON_AREA: u32 = 400;
+/// Maximum radius of the circle weapons, a circle of this radius fits into
+/// `MAX_WEAPON_AREA`.
+pub const MAX_WEAPON_RADIUS: u8 = 11;
+
+/// Area action painting many pixels at once. Painted pixels are owned by
+/// the account using the weapon, bombed pixels go back to the contract.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde", tag = "kind", rename_all = "snake_case")]
+pub enum Weapon {
+    FillRect {
+        rect: Rect,
+        color: u32,
+    },
+    /// paints pixels within `radius` from the center (x, y)
+    Circle {
+        x: u8,
+        y: u8,
+        radius: u8,
+        color: u32,
+    },
+    /// clears pixels within `radius` from the center (x, y) to white
+    Bomb {
+        x: u8,
+        y: u8,
+        radius: u8,
+    },
+}
+
+impl Weapon {
+    /// Returns the affected pixels, clipped to the board.
+    pub fn pixels(&self) -> Vec<SetPixelRequest> {
+        let pixel = |x, y, color| SetPixelRequest {
+            x,
+            y,
+            color,
+            price: None,
+        };
+        let pixels: Vec<SetPixelRequest> = match *self {
+            Weapon::FillRect { rect, color } => {
+                rect.assert_valid();
+                (rect.y..rect.y + rect.height)
+                    .flat_map(|y| (rect.x..rect.x + rect.width).map(move |x| pixel(x, y, color)))
+                    .collect()
+            }
+            Weapon::Circle {
+                x,
+                y,
+                radius,
+                color,
+            } => circle(x, y, radius)
+                .map(|(x, y)| pixel(x, y, color))
+                .collect(),
+            Weapon::Bomb { x, y, radius } => circle(x, y, radius)
+                .map(|(x, y)| pixel(x, y, Pixel::default().color))
+                .collect(),
+        };
+        assert!(
+            pixels.len() as u32 <= MAX_WEAPON_AREA,
+            "Weapon area is too large"
+        );
+        pixels
+    }
+}
+
+/// Board coordinates within `radius` from (cx, cy).
+fn circle(cx: u8, cy: u8, radius: u8) -> impl Iterator<Item = (u8, u8)> {
+    assert!(
+        cx < BOARD_WIDTH && cy < BOARD_HEIGHT,
+        "Center is out of bounds"
+    );
+    assert!(radius <= MAX_WEAPON_RADIUS, "Weapon radius is too large");
+    let r = i32::from(radius);
+    let (cx, cy) = (i32::from(cx), i32::from(cy));
+    (cy - r..=cy + r)
+        .flat_map(move |y| (cx - r..=cx + r).map(move |x| (x, y)))
+        .filter(move |(x, y)| {
+            (x - cx).pow(2) + (y - cy).pow(2) <= r * r
+                && (0..i32::from(BOARD_WIDTH)).contains(x)
+                && (0..i32::from(BOARD_HEIGHT)).contains(y)
+        })
+        .map(|(x, y)| (x as u8, y as u8))
+}
+
+impl Place {
+    /// Milk the account pays for drawing the pixels (including the declared
+    /// prices of taken over pixels) and the weapon surcharge on top of it.
+    fn weapon_cost(
+        &self,
+        account_index: AccountIndex,
+        pixels: &[SetPixelRequest],
+    ) -> (Balance, Balance) {
+        let cost = self.board.quote(
+            account_index,
+            pixels,
+            &self.pixel_pricing,
+            self.harberger_tax_rate > 0,
+        );
+        let surcharge = (cost * Balance::from(self.weapon_surcharge)).div_ceil(10_000);
+        (cost, surcharge)
+    }
+}
+
+#[near_bindgen]
+impl Place {
+    /// Paints all pixels affected by the weapon. Costs the same as drawing the
+    /// pixels one by one plus the area surcharge.
+    pub fn use_weapon(&mut self, weapon: Weapon) {
+        self.assert_active();
+        let account_id = env::predecessor_account_id();
+        assert!(!self.blacklist.contains(&account_id), "Account blacklisted");
+
+        let pixels = weapon.pixels();
+        if pixels.is_empty() {
+            return;
+        }
+        let mut account = self.get_mut_account(&account_id);
+        let (_, surcharge) = self.weapon_cost(account.account_index, &pixels);
+        account.charge(Berry::Milk, surcharge);
+        self.save_account(account);
+        self.used_milk += surcharge;
+        self.record_daily(|s| s.milk_used = (s.milk_used.0 + surcharge).into());
+
+        match weapon {
+            Weapon::Bomb { .. } => self.internal_clear(&account_id, &pixels),
+            _ => self.internal_draw(&account_id, &pixels),
+        }
+    }
+
+    /// Returns the amount of Milk the account would pay for using the weapon.
+    pub fn quote_weapon(&self, account_id: ValidAccountId, weapon: Weapon) -> U128 {
+        let account_index = self
+            .account_indices
+            .get(account_id.as_ref())
+            .unwrap_or_else(|| self.next_account_index());
+        let (cost, surcharge) = self.weapon_cost(account_index, &weapon.pixels());
+        (cost + surcharge).into()
+    }
+
+    /// Sets the weapon surcharge in basis points of the pixels cost.
+    pub fn set_weapon_surcharge(&mut self, weapon_surcharge: u32) {
+        self.only_admin();
+        self.weapon_surcharge = weapon_surcharge;
+    }
+}